@@ -0,0 +1,114 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use partial_frame_utils::{FrameContext, Grid, Merge};
+use std::time::Duration;
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+
+fn desktop() -> image::RgbImage {
+    image::RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        image::Rgb([(x % 251) as u8, (y % 241) as u8, ((x ^ y) % 239) as u8])
+    })
+}
+
+fn push(c: &mut Criterion) {
+    let frame = desktop();
+    let mut caret = frame.clone();
+    for y in 500..518 {
+        caret.put_pixel(960, y, image::Rgb([255, 255, 255]));
+        caret.put_pixel(961, y, image::Rgb([255, 255, 255]));
+    }
+
+    // What a single full-frame copy costs; push used to make up to three.
+    c.bench_function("clone 1080p", |b| b.iter(|| frame.clone()));
+
+    c.bench_function("push 1080p unchanged", |b| {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), usize::MAX, frame.clone());
+        b.iter_batched(
+            || frame.clone(),
+            |next| ctx.push(&Duration::from_secs(1), next),
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("push 1080p caret", |b| {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), usize::MAX, frame.clone());
+        let mut frames = vec![caret.clone(), frame.clone()].into_iter().cycle();
+        b.iter_batched(
+            || frames.next().unwrap(),
+            |next| ctx.push(&Duration::from_secs(1), next),
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("push 1080p caret with motion", |b| {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), usize::MAX, frame.clone());
+        ctx.motion = true;
+        let mut frames = vec![caret.clone(), frame.clone()].into_iter().cycle();
+        b.iter_batched(
+            || frames.next().unwrap(),
+            |next| ctx.push(&Duration::from_secs(1), next),
+            BatchSize::LargeInput,
+        )
+    });
+
+    // A pseudo-random fifth of the 16 px tiles dirty; merging them used to
+    // rescan every pair of rectangles after each merge and took seconds.
+    let mut scattered = frame.clone();
+    let mut state = 12345u32;
+    for y_idx in 0..HEIGHT.div_ceil(16) {
+        for x_idx in 0..WIDTH / 16 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            if (state >> 16).is_multiple_of(5) {
+                scattered.put_pixel(x_idx * 16, y_idx * 16, image::Rgb([255, 255, 255]));
+            }
+        }
+    }
+    c.bench_function("push 1080p scattered with merge", |b| {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), usize::MAX, frame.clone());
+        ctx.grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+        ctx.merge = Some(Merge {
+            max_clean_ratio: 0.6,
+        });
+        let mut frames = vec![scattered.clone(), frame.clone()].into_iter().cycle();
+        b.iter_batched(
+            || frames.next().unwrap(),
+            |next| ctx.push(&Duration::from_secs(1), next),
+            BatchSize::LargeInput,
+        )
+    });
+
+    // Hash mode at 4K, where a 60 fps capture leaves about 16 ms per frame.
+    let frame_4k = image::RgbImage::from_fn(3840, 2160, |x, y| {
+        image::Rgb([(x % 251) as u8, (y % 241) as u8, ((x ^ y) % 239) as u8])
+    });
+    let mut caret_4k = frame_4k.clone();
+    for y in 1000..1036 {
+        caret_4k.put_pixel(1920, y, image::Rgb([255, 255, 255]));
+    }
+    c.bench_function("push 4K caret with hash tiles", |b| {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), usize::MAX, frame_4k.clone());
+        ctx.hash_tiles = true;
+        let mut frames = vec![caret_4k.clone(), frame_4k.clone()].into_iter().cycle();
+        b.iter_batched(
+            || frames.next().unwrap(),
+            |next| ctx.push(&Duration::from_secs(1), next),
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("push 1080p key frame", |b| {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 0, frame.clone());
+        b.iter_batched(
+            || frame.clone(),
+            |next| ctx.push(&Duration::from_secs(1), next),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, push);
+criterion_main!(benches);
@@ -0,0 +1,264 @@
+use crate::{Frame, FrameContext};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Identifies a subscriber of a `Broadcaster`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubscriberId(u64);
+
+/// What a `Broadcaster` knows about one subscriber.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscriber {
+    /// Frames between this subscriber's key frames.
+    pub limits: usize,
+    /// Most pushes sent since the last acknowledged frame or key frame
+    /// before the subscriber is assumed to have lost one and gets a key
+    /// frame. `None` leaves loss handling to `Broadcaster::request_keyframe`.
+    pub max_lag: Option<u64>,
+    current: usize,
+    acked: Option<u64>,
+    /// Sequence number of the last key frame sent.
+    keyframe_at: u64,
+    keyframe_pending: bool,
+}
+
+impl Subscriber {
+    /// Sequence number of the last frame the subscriber acknowledged.
+    pub fn acked(&self) -> Option<u64> {
+        self.acked
+    }
+
+    /// Whether the next push sends this subscriber a key frame.
+    pub fn keyframe_pending(&self) -> bool {
+        self.keyframe_pending
+    }
+
+    fn needs_key(&self, sequence: u64) -> bool {
+        let since = self.acked.unwrap_or(0).max(self.keyframe_at);
+        self.keyframe_pending
+            || self.current >= self.limits
+            || self
+                .max_lag
+                .is_some_and(|max_lag| sequence.saturating_sub(since) > max_lag)
+    }
+}
+
+/// Sends one capture to many subscribers, diffing each frame only once.
+///
+/// Subscribers that are in sync share the partial frames of `context`; one
+/// that just joined, asked for a key frame, reached its own `limits` or fell
+/// more than `max_lag` pushes behind with its acks gets a key frame of the
+/// reference instead. Subscribers getting the same frame share one `Arc`, so
+/// a push makes at most two: the delta and the key frame.
+///
+/// `context` is configured as usual, except that `limits` stays at
+/// `usize::MAX`: key frames from `context` itself (resizes, its
+/// `keyframe_policy`) go to every subscriber.
+pub struct Broadcaster<P: image::Pixel> {
+    pub context: FrameContext<P>,
+    subscribers: BTreeMap<SubscriberId, Subscriber>,
+    next_id: u64,
+    sequence: u64,
+}
+
+impl<P: 'static + image::Pixel> Broadcaster<P>
+where
+    P: image::Pixel + std::cmp::PartialEq + Send + Sync,
+    <P as image::Pixel>::Subpixel: Send + Sync,
+{
+    pub fn new(
+        timestamp: Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    ) -> Self {
+        Broadcaster {
+            context: FrameContext::new(timestamp, usize::MAX, frame),
+            subscribers: BTreeMap::new(),
+            next_id: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Adds a subscriber whose first frame is a key frame.
+    pub fn subscribe(&mut self, limits: usize) -> SubscriberId {
+        let id = SubscriberId(self.next_id);
+        self.next_id += 1;
+        self.subscribers.insert(
+            id,
+            Subscriber {
+                limits,
+                max_lag: None,
+                current: 0,
+                acked: None,
+                keyframe_at: self.sequence,
+                keyframe_pending: true,
+            },
+        );
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriberId) -> bool {
+        self.subscribers.remove(&id).is_some()
+    }
+
+    pub fn subscriber(&self, id: SubscriberId) -> Option<&Subscriber> {
+        self.subscribers.get(&id)
+    }
+
+    pub fn subscriber_mut(&mut self, id: SubscriberId) -> Option<&mut Subscriber> {
+        self.subscribers.get_mut(&id)
+    }
+
+    /// Sequence number of the last push, starting at 1.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Records that `id` received the frame of push `sequence`. Acks for
+    /// pushes that have not happened yet are ignored.
+    pub fn ack(&mut self, id: SubscriberId, sequence: u64) {
+        if sequence > self.sequence {
+            return;
+        }
+        if let Some(subscriber) = self.subscribers.get_mut(&id) {
+            subscriber.acked = subscriber.acked.max(Some(sequence));
+        }
+    }
+
+    /// Sends `id` a key frame on the next push, e.g. after it lost a frame.
+    pub fn request_keyframe(&mut self, id: SubscriberId) {
+        if let Some(subscriber) = self.subscribers.get_mut(&id) {
+            subscriber.keyframe_pending = true;
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        timestamp: &Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    ) -> Vec<(SubscriberId, Arc<Frame<P>>)> {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        let needs_key = self
+            .subscribers
+            .values()
+            .any(|subscriber| subscriber.needs_key(sequence));
+        // Without a reference in `hash_tiles` mode the frame is the key frame.
+        let input = if needs_key && self.context.hash_tiles {
+            Some(frame.clone())
+        } else {
+            None
+        };
+
+        let delta = Arc::new(self.context.push(timestamp, frame));
+        let key = match *delta {
+            Frame::KeyFrame(_) => Some(delta.clone()),
+            _ if needs_key => self
+                .context
+                .frame
+                .clone()
+                .or(input)
+                .map(|reference| Arc::new(Frame::KeyFrame(reference))),
+            _ => None,
+        };
+
+        let idle_counts = self.context.count_unchanged;
+        self.subscribers
+            .iter_mut()
+            .map(|(id, subscriber)| {
+                let frame = match &key {
+                    Some(key) if Arc::ptr_eq(key, &delta) || subscriber.needs_key(sequence) => {
+                        subscriber.current = 0;
+                        subscriber.keyframe_at = sequence;
+                        subscriber.keyframe_pending = false;
+                        key.clone()
+                    }
+                    _ => {
+                        if idle_counts || !matches!(*delta, Frame::Unchanged) {
+                            subscriber.current += 1;
+                        }
+                        delta.clone()
+                    }
+                };
+                (*id, frame)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Broadcaster, Frame};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn per_subscriber_key_frames() {
+        let mut broadcaster =
+            Broadcaster::new(Duration::from_secs(0), image::RgbImage::new(32, 32));
+        let early = broadcaster.subscribe(2);
+        let is_key = |frame: &Arc<Frame<image::Rgb<u8>>>| matches!(**frame, Frame::KeyFrame(_));
+
+        let mut img = image::RgbImage::new(32, 32);
+        img.put_pixel(1, 1, image::Rgb([255, 255, 255]));
+        let frames = broadcaster.push(&Duration::from_secs(1), img.clone());
+        assert_eq!(frames.len(), 1);
+        assert!(is_key(&frames[0].1));
+
+        let late = broadcaster.subscribe(100);
+        img.put_pixel(2, 2, image::Rgb([255, 255, 255]));
+        let frames = broadcaster.push(&Duration::from_secs(2), img.clone());
+        assert_eq!(frames[0].0, early);
+        assert!(!is_key(&frames[0].1));
+        assert_eq!(frames[1].0, late);
+        match &*frames[1].1 {
+            Frame::KeyFrame(key) => assert_eq!(key, &img),
+            _ => panic!("expected key frame"),
+        }
+
+        img.put_pixel(3, 3, image::Rgb([255, 255, 255]));
+        let frames = broadcaster.push(&Duration::from_secs(3), img.clone());
+        assert!(!is_key(&frames[0].1));
+        assert!(Arc::ptr_eq(&frames[0].1, &frames[1].1));
+
+        // `early` reached its limits, `late` asked for a key frame after a loss.
+        broadcaster.request_keyframe(late);
+        let frames = broadcaster.push(&Duration::from_secs(4), img);
+        assert!(is_key(&frames[0].1) && is_key(&frames[1].1));
+
+        broadcaster.ack(late, 4);
+        assert_eq!(broadcaster.subscriber(late).unwrap().acked(), Some(4));
+        assert!(broadcaster.unsubscribe(early));
+        assert_eq!(broadcaster.sequence(), 4);
+    }
+
+    #[test]
+    fn lagging_subscriber() {
+        let mut broadcaster =
+            Broadcaster::new(Duration::from_secs(0), image::RgbImage::new(32, 32));
+        let id = broadcaster.subscribe(100);
+        broadcaster.subscriber_mut(id).unwrap().max_lag = Some(2);
+
+        let mut keys = Vec::new();
+        for n in 1..=8u8 {
+            let mut img = image::RgbImage::new(32, 32);
+            img.put_pixel(n as u32, 0, image::Rgb([n, n, n]));
+            let frames = broadcaster.push(&Duration::from_secs(n as u64), img);
+            keys.push(matches!(*frames[0].1, Frame::KeyFrame(_)));
+            // Acks stop after the fourth push.
+            if n <= 4 {
+                broadcaster.ack(id, broadcaster.sequence());
+            }
+        }
+        assert_eq!(
+            keys,
+            vec![true, false, false, false, false, false, true, false]
+        );
+
+        // An ack from the future is ignored rather than trusted.
+        broadcaster.ack(id, 100);
+        assert_eq!(broadcaster.subscriber(id).unwrap().acked(), Some(4));
+        let frames = broadcaster.push(&Duration::from_secs(9), image::RgbImage::new(32, 32));
+        assert!(!matches!(*frames[0].1, Frame::KeyFrame(_)));
+    }
+}
@@ -0,0 +1,96 @@
+use num_traits::ToPrimitive;
+
+/// Returns `true` when two pixels should be treated as different.
+pub type PixelPredicate<P> = dyn Fn(&P, &P) -> bool + Send + Sync;
+
+/// Decides whether two pixels differ enough to mark their tile dirty.
+#[derive(Default)]
+pub enum Comparator<P: image::Pixel> {
+    /// Any change in any channel.
+    #[default]
+    Exact,
+    /// The largest per-channel absolute difference exceeds the threshold.
+    Channel(f64),
+    /// The Euclidean distance over all channels exceeds the threshold.
+    Euclidean(f64),
+    /// The Euclidean distance in YUV (BT.601) space exceeds the threshold.
+    /// Alpha is ignored.
+    Yuv(f64),
+    /// A user-supplied predicate returning `true` when the pixels differ.
+    Custom(Box<PixelPredicate<P>>),
+}
+
+#[inline(always)]
+fn to_f64<T: ToPrimitive>(value: T) -> f64 {
+    value.to_f64().unwrap_or(0.0)
+}
+
+impl<P> Comparator<P>
+where
+    P: image::Pixel + std::cmp::PartialEq,
+{
+    /// Returns `true` if every change is reported, so the new frame can be
+    /// kept as the reference as-is.
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Comparator::Exact)
+    }
+
+    pub fn differs(&self, p: &P, q: &P) -> bool {
+        match self {
+            Comparator::Exact => p != q,
+            Comparator::Channel(threshold) => p
+                .channels()
+                .iter()
+                .zip(q.channels())
+                .any(|(a, b)| (to_f64(*a) - to_f64(*b)).abs() > *threshold),
+            Comparator::Euclidean(threshold) => {
+                let sum: f64 = p
+                    .channels()
+                    .iter()
+                    .zip(q.channels())
+                    .map(|(a, b)| (to_f64(*a) - to_f64(*b)).powi(2))
+                    .sum();
+                sum > threshold * threshold
+            }
+            Comparator::Yuv(threshold) => {
+                let (p, q) = (p.to_rgb(), q.to_rgb());
+                let r = to_f64(p[0]) - to_f64(q[0]);
+                let g = to_f64(p[1]) - to_f64(q[1]);
+                let b = to_f64(p[2]) - to_f64(q[2]);
+                let y = 0.299 * r + 0.587 * g + 0.114 * b;
+                let u = -0.14713 * r - 0.28886 * g + 0.436 * b;
+                let v = 0.615 * r - 0.51499 * g - 0.10001 * b;
+                y * y + u * u + v * v > threshold * threshold
+            }
+            Comparator::Custom(f) => f(p, q),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Comparator;
+    use image::Rgb;
+
+    #[test]
+    fn thresholds() {
+        let p = Rgb([100u8, 100, 100]);
+        let q = Rgb([103u8, 100, 96]);
+
+        assert!(Comparator::Exact.differs(&p, &q));
+        assert!(!Comparator::Channel(4.0).differs(&p, &q));
+        assert!(Comparator::Channel(3.0).differs(&p, &q));
+        assert!(!Comparator::Euclidean(5.0).differs(&p, &q));
+        assert!(Comparator::Euclidean(4.9).differs(&p, &q));
+        assert!(!Comparator::Yuv(5.0).differs(&p, &p));
+        // BT.601 puts (3, 0, -4) at a YUV distance of about 3.16.
+        assert!(!Comparator::Yuv(3.2).differs(&p, &q));
+        assert!(Comparator::Yuv(3.1).differs(&p, &q));
+        // The same step moves further in YUV in green than in blue.
+        let green = Rgb([100u8, 104, 100]);
+        let blue = Rgb([100u8, 100, 104]);
+        assert!(Comparator::Yuv(3.0).differs(&p, &green));
+        assert!(!Comparator::Yuv(3.0).differs(&p, &blue));
+        assert!(Comparator::Custom(Box::new(|p: &Rgb<u8>, q| p[0] != q[0])).differs(&p, &q));
+    }
+}
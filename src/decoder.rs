@@ -0,0 +1,161 @@
+use crate::{apply_copies, Frame, Rect};
+
+/// Why a `FrameDecoder` rejected a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A partial or copy frame arrived before any key frame.
+    NoKeyFrame,
+    /// A partial frame or copy reaches outside the canvas.
+    OutOfBounds(Rect),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::NoKeyFrame => write!(f, "frame received before the first key frame"),
+            DecodeError::OutOfBounds(rect) => write!(
+                f,
+                "{}x{} at {},{} is outside the frame",
+                rect.width, rect.height, rect.x, rect.y
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Rebuilds full frames from the output of `FrameContext::push`.
+pub struct FrameDecoder<P: image::Pixel> {
+    frame: Option<image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>>,
+}
+
+impl<P: image::Pixel> Default for FrameDecoder<P> {
+    fn default() -> Self {
+        FrameDecoder { frame: None }
+    }
+}
+
+impl<P: 'static + image::Pixel> FrameDecoder<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The reconstructed frame; `None` until the first key frame.
+    pub fn frame(&self) -> Option<&image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>> {
+        self.frame.as_ref()
+    }
+
+    pub fn into_frame(self) -> Option<image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>> {
+        self.frame
+    }
+
+    /// Applies `frame` to the canvas. A rejected frame leaves it untouched.
+    pub fn apply(&mut self, frame: &Frame<P>) -> Result<(), DecodeError> {
+        let (copies, partials) = match frame {
+            Frame::KeyFrame(key) => {
+                // A key frame of a new size replaces the canvas.
+                match self.frame.as_mut() {
+                    Some(canvas) if canvas.dimensions() == key.dimensions() => {
+                        canvas.copy_from_slice(key);
+                    }
+                    _ => self.frame = Some(key.clone()),
+                }
+                return Ok(());
+            }
+            Frame::Unchanged => return Ok(()),
+            Frame::PartialFrame(partials) => (&[][..], partials),
+            Frame::CopyFrame(copies, partials) => (&copies[..], partials),
+        };
+        let canvas = self.frame.as_mut().ok_or(DecodeError::NoKeyFrame)?;
+        let (width, height) = canvas.dimensions();
+        let rects = copies
+            .iter()
+            .flat_map(|copy| {
+                vec![
+                    copy.src,
+                    Rect::new(copy.x, copy.y, copy.src.width, copy.src.height),
+                ]
+            })
+            .chain(partials.iter().map(|partial| {
+                let (width, height) = partial.image.dimensions();
+                Rect::new(partial.x, partial.y, width, height)
+            }));
+        for rect in rects {
+            // The frame may come from anywhere; its edges can overflow.
+            let fits = rect
+                .x
+                .checked_add(rect.width)
+                .is_some_and(|right| right <= width)
+                && rect
+                    .y
+                    .checked_add(rect.height)
+                    .is_some_and(|bottom| bottom <= height);
+            if !fits {
+                return Err(DecodeError::OutOfBounds(rect));
+            }
+        }
+
+        apply_copies(canvas, copies);
+        for partial in partials {
+            image::imageops::replace(canvas, &partial.image, partial.x, partial.y);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        CopyRect, DecodeError, Frame, FrameContext, FrameDecoder, PartialFrame, Rect, Scroll,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn round_trip() {
+        let mut decoder = FrameDecoder::new();
+        let partial = Frame::PartialFrame(vec![PartialFrame {
+            x: 0,
+            y: 0,
+            image: image::RgbImage::new(4, 4),
+        }]);
+        assert_eq!(decoder.apply(&partial), Err(DecodeError::NoKeyFrame));
+
+        let first = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 10, first.clone());
+        ctx.scroll = Some(Scroll::default());
+        decoder.apply(&Frame::KeyFrame(first)).unwrap();
+
+        for n in 1..4u32 {
+            let img = image::RgbImage::from_fn(64, 64, |x, y| {
+                image::Rgb([x as u8, (y + 5 * n) as u8, (n * 40) as u8 * (x > 60) as u8])
+            });
+            decoder
+                .apply(&ctx.push(&Duration::from_secs(n as u64), img.clone()))
+                .unwrap();
+            assert_eq!(decoder.frame(), Some(&img));
+        }
+
+        let outside = Frame::PartialFrame(vec![PartialFrame {
+            x: 62,
+            y: 0,
+            image: image::RgbImage::new(4, 4),
+        }]);
+        assert_eq!(
+            decoder.apply(&outside),
+            Err(DecodeError::OutOfBounds(Rect::new(62, 0, 4, 4)))
+        );
+
+        let overflowing = Frame::CopyFrame(
+            vec![CopyRect {
+                src: Rect::new(0, 0, 4, 4),
+                x: u32::MAX - 1,
+                y: 0,
+            }],
+            Vec::new(),
+        );
+        assert_eq!(
+            decoder.apply(&overflowing),
+            Err(DecodeError::OutOfBounds(Rect::new(u32::MAX - 1, 0, 4, 4)))
+        );
+    }
+}
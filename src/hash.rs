@@ -0,0 +1,148 @@
+use num_traits::ToPrimitive;
+use std::any::Any;
+
+const SEED: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hashes raw subpixels, continuing from `state`. Start with `hash_start()`.
+///
+/// Values are hashed through their `f64` bit patterns so every subpixel type
+/// is supported; equal inputs always hash equal, but callers must still verify
+/// matches since this is not collision free.
+#[inline]
+pub(crate) fn hash_subpixels<T: ToPrimitive + Copy>(state: u64, data: &[T]) -> u64 {
+    data.iter().fold(state, |state, value| {
+        let bits = value.to_f64().unwrap_or(0.0).to_bits();
+        (state ^ bits).wrapping_mul(PRIME).rotate_left(29)
+    })
+}
+
+#[inline(always)]
+pub(crate) fn hash_start() -> u64 {
+    SEED
+}
+
+/// Hashes 64-bit words, continuing from `state`.
+///
+/// Each step is a bijection of the state, so inputs that differ in a single
+/// word never hash equal.
+#[inline(always)]
+fn hash_words(state: u64, words: impl Iterator<Item = u64>) -> u64 {
+    words.fold(state, step)
+}
+
+#[inline(always)]
+fn step(state: u64, word: u64) -> u64 {
+    (state.rotate_left(26) ^ word).wrapping_mul(PRIME)
+}
+
+/// Hashes `rows` packed `LEN` values to a word by `word`. Words are spread
+/// over four lanes, so the multiplications don't wait on each other, and the
+/// lanes are only combined at the end.
+#[inline(always)]
+fn hash_packed<'a, T: 'a + Copy, const LEN: usize>(
+    rows: impl Iterator<Item = &'a [T]>,
+    word: impl Fn([T; LEN]) -> u64,
+) -> u64 {
+    let pack = |values: &[T]| {
+        let mut packed = [values[0]; LEN];
+        packed[..values.len()].copy_from_slice(values);
+        word(packed)
+    };
+    let mut lanes = [0u64; 4];
+    for row in rows {
+        let mut chunks = row.chunks_exact(4 * LEN);
+        for chunk in &mut chunks {
+            let (a, rest) = chunk.split_at(LEN);
+            let (b, rest) = rest.split_at(LEN);
+            let (c, d) = rest.split_at(LEN);
+            lanes = [
+                step(lanes[0], pack(a)),
+                step(lanes[1], pack(b)),
+                step(lanes[2], pack(c)),
+                step(lanes[3], pack(d)),
+            ];
+        }
+        // The rest of a row packs into at most one word per lane.
+        for (lane, values) in lanes.iter_mut().zip(chunks.remainder().chunks(LEN)) {
+            *lane = step(*lane, pack(values));
+        }
+    }
+    hash_words(hash_start(), lanes.iter().copied())
+}
+
+/// Hashes the pixels of `tile` row by row.
+///
+/// Integer subpixels are hashed a packed 64-bit word at a time; other
+/// subpixel types go through `hash_subpixels`.
+pub(crate) fn tile_hash<P: 'static + image::Pixel>(
+    image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    tile: &crate::Rect,
+) -> u64 {
+    let channels = P::CHANNEL_COUNT as usize;
+    let stride = image.width() as usize * channels;
+    let rows = || {
+        (tile.y..tile.bottom()).map(move |y| {
+            let start = y as usize * stride + tile.x as usize * channels;
+            start..start + tile.width as usize * channels
+        })
+    };
+
+    let raw: &dyn Any = image.as_raw();
+    macro_rules! packed {
+        ($($subpixel:ty),*) => {$(
+            if let Some(raw) = raw.downcast_ref::<Vec<$subpixel>>() {
+                const LEN: usize = 8 / std::mem::size_of::<$subpixel>();
+                return hash_packed(rows().map(|row| &raw[row]), |values: [$subpixel; LEN]| {
+                    let mut bytes = [0; 8];
+                    for (bytes, value) in bytes.chunks_exact_mut(8 / LEN).zip(&values) {
+                        bytes.copy_from_slice(&value.to_le_bytes());
+                    }
+                    u64::from_le_bytes(bytes)
+                });
+            }
+        )*};
+    }
+    packed!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+    rows().fold(hash_start(), |state, row| {
+        hash_subpixels(state, &image.as_raw()[row])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tile_hash;
+    use crate::Rect;
+
+    /// Every single subpixel change inside `tile` changes its hash, including
+    /// those in the partial words at the end of a row; changes outside don't.
+    fn detects_changes<P: 'static + image::Pixel>(image: image::ImageBuffer<P, Vec<P::Subpixel>>) {
+        let tile = Rect::new(1, 1, 35, 3);
+        let hash = tile_hash(&image, &tile);
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                for channel in 0..P::CHANNEL_COUNT as usize {
+                    let mut changed = image.clone();
+                    let value = &mut changed.get_pixel_mut(x, y).channels_mut()[channel];
+                    *value = *value + num_traits::One::one();
+                    let inside = tile.intersects(&Rect::new(x, y, 1, 1));
+                    assert_eq!(tile_hash(&changed, &tile) != hash, inside, "({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn single_subpixel_changes() {
+        detects_changes(image::RgbImage::from_fn(37, 5, |x, y| {
+            image::Rgb([x as u8, y as u8, 7])
+        }));
+        detects_changes(image::ImageBuffer::from_fn(37, 5, |x, y| {
+            image::Rgb([x as u16 * 300, y as u16, 7])
+        }));
+        detects_changes(image::ImageBuffer::from_fn(37, 5, |x, y| {
+            image::Rgb([x as f32 / 2.0, y as f32, 7.0])
+        }));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{DirtyMap, Rect};
+use std::path::Path;
+
+/// Counts how often each block of pixels was dirty over many pushes.
+///
+/// Only pushes that were diffed are recorded; key frames sent because of
+/// `limits`, a resize or a request are not. A resize starts over.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heatmap {
+    block_size: u32,
+    width: u32,
+    height: u32,
+    columns: u32,
+    rows: u32,
+    counts: Vec<u64>,
+    samples: u64,
+}
+
+impl Heatmap {
+    /// Creates an empty heatmap of `block_size` x `block_size` pixel blocks.
+    pub fn new(block_size: u32) -> Self {
+        Heatmap {
+            block_size: block_size.max(1),
+            width: 0,
+            height: 0,
+            columns: 0,
+            rows: 0,
+            counts: Vec::new(),
+            samples: 0,
+        }
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    /// Number of pushes recorded.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// How often each block was dirty, in row-major order.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn get(&self, x_idx: u32, y_idx: u32) -> u64 {
+        self.counts[(y_idx * self.columns + x_idx) as usize]
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.samples = 0;
+    }
+
+    /// Adds one push whose changes lie within `dirty`, in pixels.
+    pub(crate) fn record(&mut self, width: u32, height: u32, dirty: &[Rect]) {
+        if (width, height) != (self.width, self.height) {
+            let columns = width.div_ceil(self.block_size);
+            let rows = height.div_ceil(self.block_size);
+            *self = Heatmap {
+                width,
+                height,
+                columns,
+                rows,
+                counts: vec![0; columns as usize * rows as usize],
+                ..Heatmap::new(self.block_size)
+            };
+        }
+        // A block touched by several rectangles still counts once.
+        let mut blocks = DirtyMap::new(self.columns, self.rows);
+        for rect in dirty.iter().filter(|rect| !rect.is_empty()) {
+            for y_idx in rect.y / self.block_size..=(rect.bottom() - 1) / self.block_size {
+                for x_idx in rect.x / self.block_size..=(rect.right() - 1) / self.block_size {
+                    blocks.set(x_idx, y_idx);
+                }
+            }
+        }
+        for (x_idx, y_idx) in blocks.iter() {
+            self.counts[(y_idx * self.columns + x_idx) as usize] += 1;
+        }
+        self.samples += 1;
+    }
+
+    /// Renders the counts at frame size, from black (never dirty) through
+    /// blue, red and yellow to white (dirty on every recorded push).
+    pub fn image(&self) -> image::RgbImage {
+        const STOPS: [[f32; 3]; 5] = [
+            [0.0, 0.0, 0.0],
+            [0.0, 0.0, 255.0],
+            [255.0, 0.0, 0.0],
+            [255.0, 255.0, 0.0],
+            [255.0, 255.0, 255.0],
+        ];
+        let samples = self.samples.max(1) as f32;
+        image::RgbImage::from_fn(self.width, self.height, |x, y| {
+            let heat = self.get(x / self.block_size, y / self.block_size) as f32 / samples;
+            let position = heat * (STOPS.len() - 1) as f32;
+            let index = (position as usize).min(STOPS.len() - 2);
+            let t = position - index as f32;
+            let (from, to) = (STOPS[index], STOPS[index + 1]);
+            image::Rgb([0, 1, 2].map(|c| (from[c] + (to[c] - from[c]) * t).round() as u8))
+        })
+    }
+
+    /// Saves `image`, e.g. as a PNG.
+    pub fn save<Q: AsRef<Path>>(&self, path: Q) -> image::ImageResult<()> {
+        self.image().save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heatmap::Heatmap;
+    use crate::Rect;
+
+    #[test]
+    fn counts_blocks() {
+        let mut heatmap = Heatmap::new(8);
+        heatmap.record(20, 10, &[Rect::new(0, 0, 9, 1), Rect::new(4, 4, 2, 2)]);
+        heatmap.record(20, 10, &[Rect::new(19, 9, 1, 1)]);
+        assert_eq!((heatmap.columns(), heatmap.rows()), (3, 2));
+        assert_eq!(heatmap.counts(), &[1, 1, 0, 0, 0, 1]);
+        assert_eq!(heatmap.samples(), 2);
+
+        let image = heatmap.image();
+        assert_eq!(image.dimensions(), (20, 10));
+        assert_eq!(image.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+        assert_eq!(image.get_pixel(18, 0), &image::Rgb([0, 0, 0]));
+
+        heatmap.record(10, 10, &[]);
+        assert_eq!(heatmap.counts(), &[0, 0, 0, 0]);
+    }
+}
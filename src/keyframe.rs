@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+/// Why a push returned a key frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeReason {
+    /// `FrameContext::limits` partial frames were sent.
+    Limits,
+    /// The frame size changed.
+    Resized,
+    /// `FrameContext::request_keyframe` was called.
+    Requested,
+    /// `KeyframePolicy::max_interval` passed.
+    Interval,
+    /// `KeyframePolicy::max_dirty_ratio` was reached.
+    DirtyRatio,
+    /// `KeyframePolicy::max_payload` was reached.
+    Payload,
+}
+
+/// When to send a key frame before `FrameContext::limits` is reached.
+///
+/// A key frame is sent as soon as any of the set thresholds is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct KeyframePolicy {
+    /// Share of dirty tiles, from `0.0` to `1.0`.
+    pub max_dirty_ratio: Option<f32>,
+    /// Bytes of pixel data the partial frames would hold.
+    pub max_payload: Option<usize>,
+    /// Longest time between key frames, measured with the timestamps passed
+    /// to `push`.
+    pub max_interval: Option<Duration>,
+}
+
+impl KeyframePolicy {
+    pub(crate) fn triggered(&self, dirty_ratio: f32, payload: usize) -> Option<KeyframeReason> {
+        if self
+            .max_dirty_ratio
+            .is_some_and(|max_dirty_ratio| dirty_ratio >= max_dirty_ratio)
+        {
+            Some(KeyframeReason::DirtyRatio)
+        } else if self
+            .max_payload
+            .is_some_and(|max_payload| payload >= max_payload)
+        {
+            Some(KeyframeReason::Payload)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn expired(&self, elapsed: Duration) -> bool {
+        self.max_interval
+            .is_some_and(|max_interval| elapsed >= max_interval)
+    }
+}
@@ -1,13 +1,79 @@
 use log::trace;
-use std::time::Duration;
+use mask::{Coverage, Masks};
+use motion::MotionIndex;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+mod broadcast;
+mod compare;
+mod decoder;
+mod hash;
+mod heatmap;
+mod keyframe;
+mod mask;
+mod motion;
+mod overlay;
+mod region;
+mod stats;
+mod verify;
+
+pub use broadcast::{Broadcaster, Subscriber, SubscriberId};
+pub use compare::{Comparator, PixelPredicate};
+pub use decoder::{DecodeError, FrameDecoder};
+pub use heatmap::Heatmap;
+pub use keyframe::{KeyframePolicy, KeyframeReason};
+pub use mask::{Ignore, Mask};
+pub use motion::Scroll;
+pub use overlay::{debug_overlay, save_debug_overlay};
+pub use region::Merge;
+pub use stats::FrameStats;
+pub use verify::{Verifier, VerifyError};
 
 pub struct FrameContext<P: image::Pixel> {
     pub current: usize,
     pub limits: usize,
     pub timestamp: Duration,
-    pub frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    /// The reference the next frame is compared against; `None` when
+    /// `hash_tiles` is set, as only tile hashes are kept then.
+    pub frame: Option<image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>>,
     pub width: u32,
     pub height: u32,
+    pub grid: Grid,
+    pub compare: Comparator<P>,
+    pub merge: Option<Merge>,
+    /// Shrink each emitted rectangle to the bounding box of its changed pixels.
+    /// The changed pixels are then also counted for `FrameStats`.
+    pub tight: bool,
+    /// Detect scrolled regions and send them as `CopyRect`s.
+    pub scroll: Option<Scroll>,
+    /// Look up the tiles of the previous frame around the dirty tiles and
+    /// send the ones found as `CopyRect`s, e.g. for dragged windows.
+    pub motion: bool,
+    motion_index: Option<MotionIndex>,
+    /// Keep a content hash per tile instead of the previous frame, and mark a
+    /// tile dirty when its hash changes. Detection is exact (up to hash
+    /// collisions); `compare`, `tight`, `scroll` and `motion` need the
+    /// previous frame and are not used in this mode.
+    pub hash_tiles: bool,
+    hashes: Option<TileHashes>,
+    /// Regions whose changes are not reported, or only now and then. In
+    /// `hash_tiles` mode only tiles fully inside a mask are ignored.
+    pub ignore: Vec<Ignore>,
+    pushes: usize,
+    /// Sends a key frame before `limits` is reached when a partial update
+    /// would not pay off or too much time has passed.
+    pub keyframe_policy: KeyframePolicy,
+    /// Timestamp of the last key frame.
+    keyframe_at: Duration,
+    keyframe_requested: bool,
+    /// Whether `Frame::Unchanged` pushes count toward `limits` and
+    /// `keyframe_policy.max_interval`. Defaults to `true`; with `false` an
+    /// idle screen gets no key frames until it changes again.
+    pub count_unchanged: bool,
+    stats: FrameStats,
+    /// Counts the dirty areas of every diffed push when set.
+    pub heatmap: Option<Heatmap>,
 }
 
 impl<P: 'static + image::Pixel> FrameContext<P>
@@ -25,98 +91,971 @@ where
             timestamp,
             width: frame.width(),
             height: frame.height(),
-            frame,
+            frame: Some(frame),
+            grid: Grid::default(),
+            compare: Comparator::default(),
+            merge: None,
+            tight: false,
+            scroll: None,
+            motion: false,
+            motion_index: None,
+            hash_tiles: false,
+            hashes: None,
+            ignore: Vec::new(),
+            pushes: 0,
+            keyframe_policy: KeyframePolicy::default(),
+            keyframe_at: timestamp,
+            keyframe_requested: false,
+            count_unchanged: true,
+            stats: FrameStats::default(),
+            heatmap: None,
         }
     }
 }
 
+/// How a frame is split into tiles for change detection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grid {
+    /// A fixed number of columns and rows, at most one per pixel. The frame is
+    /// spread evenly over them, so tile sizes differ by a pixel at most.
+    Count { columns: u32, rows: u32 },
+    /// Tiles of a fixed pixel size; the grid follows the frame size and edge tiles are clipped.
+    TileSize { width: u32, height: u32 },
+    /// Square leaves of `min_size` pixels grouped into a quadtree. A node is
+    /// emitted whole once at least `min_dirty_ratio` of its leaves are dirty,
+    /// otherwise it is subdivided down to the leaves. `FrameContext::merge` is
+    /// not used in this mode.
+    QuadTree { min_size: u32, min_dirty_ratio: f32 },
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid::Count {
+            columns: 16,
+            rows: 16,
+        }
+    }
+}
+
+/// Tile geometry of a `Grid` applied to a frame of a given size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TileLayout {
+    pub width: u32,
+    pub height: u32,
+    /// Size of the smallest whole tile. With `Grid::Count` the frame is
+    /// spread over the tiles and some are a pixel larger; otherwise only the
+    /// tiles at the right and bottom edges differ, and are clipped.
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    spread: bool,
+}
+
+impl TileLayout {
+    pub fn new(width: u32, height: u32, grid: Grid) -> Self {
+        let (tile_width, tile_height) = match grid {
+            Grid::Count { columns, rows } => {
+                // More tiles than pixels would leave some of them empty.
+                let columns = columns.max(1).min(width);
+                let rows = rows.max(1).min(height);
+                return TileLayout {
+                    width,
+                    height,
+                    tile_width: width.checked_div(columns).unwrap_or(1),
+                    tile_height: height.checked_div(rows).unwrap_or(1),
+                    columns,
+                    rows,
+                    spread: true,
+                };
+            }
+            Grid::TileSize {
+                width: tile_width,
+                height: tile_height,
+            } => (tile_width.max(1), tile_height.max(1)),
+            Grid::QuadTree { min_size, .. } => (min_size.max(1), min_size.max(1)),
+        };
+        TileLayout {
+            width,
+            height,
+            tile_width,
+            tile_height,
+            columns: width.div_ceil(tile_width),
+            rows: height.div_ceil(tile_height),
+            spread: false,
+        }
+    }
+
+    /// Left edge of column `x_idx`, or the frame width past the last one.
+    fn column_x(&self, x_idx: u32) -> u32 {
+        edge(
+            x_idx,
+            self.width,
+            self.tile_width,
+            self.columns,
+            self.spread,
+        )
+    }
+
+    /// Top edge of row `y_idx`, or the frame height past the last one.
+    fn row_y(&self, y_idx: u32) -> u32 {
+        edge(y_idx, self.height, self.tile_height, self.rows, self.spread)
+    }
+
+    /// Column of the tiles containing pixel column `x`.
+    pub fn column(&self, x: u32) -> u32 {
+        index(x, self.width, self.tile_width, self.columns, self.spread)
+    }
+
+    /// Row of the tiles containing pixel row `y`.
+    pub fn row(&self, y: u32) -> u32 {
+        index(y, self.height, self.tile_height, self.rows, self.spread)
+    }
+
+    /// Tiles touched by any of `rects`, given in pixels.
+    pub fn touched(&self, rects: &[Rect]) -> DirtyMap {
+        let mut tiles = DirtyMap::new(self.columns, self.rows);
+        for rect in rects {
+            let right = rect.right().min(self.width);
+            let bottom = rect.bottom().min(self.height);
+            if rect.x >= right || rect.y >= bottom {
+                continue;
+            }
+            for y_idx in self.row(rect.y)..=self.row(bottom - 1) {
+                for x_idx in self.column(rect.x)..=self.column(right - 1) {
+                    tiles.set(x_idx, y_idx);
+                }
+            }
+        }
+        tiles
+    }
+
+    /// Pixel bounds of a rectangle given in tile units, clipped to the frame.
+    pub fn rect(&self, tiles: Rect) -> Rect {
+        let (x, y) = (self.column_x(tiles.x), self.row_y(tiles.y));
+        Rect::new(
+            x,
+            y,
+            self.column_x(tiles.right()) - x,
+            self.row_y(tiles.bottom()) - y,
+        )
+    }
+}
+
+/// Start of tile `index` along an axis of `size` pixels split into `count`
+/// tiles, either spread evenly or `tile` pixels each.
+fn edge(index: u32, size: u32, tile: u32, count: u32, spread: bool) -> u32 {
+    if spread {
+        (index.min(count) as u64 * size as u64)
+            .checked_div(count as u64)
+            .unwrap_or(0) as u32
+    } else {
+        index.saturating_mul(tile).min(size)
+    }
+}
+
+/// The tile along an axis that pixel `position` falls in; see `edge`.
+fn index(position: u32, size: u32, tile: u32, count: u32, spread: bool) -> u32 {
+    if spread {
+        // The last tile whose edge is at or before `position`.
+        (((position as u64 + 1) * count as u64 - 1) / size as u64) as u32
+    } else {
+        position / tile
+    }
+}
+
+/// One bit per grid cell, set when the tile has changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirtyMap {
+    columns: u32,
+    rows: u32,
+    bits: Vec<u64>,
+}
+
+impl DirtyMap {
+    pub fn new(columns: u32, rows: u32) -> Self {
+        let len = columns as usize * rows as usize;
+        DirtyMap {
+            columns,
+            rows,
+            bits: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    #[inline(always)]
+    fn index(&self, x_idx: u32, y_idx: u32) -> usize {
+        y_idx as usize * self.columns as usize + x_idx as usize
+    }
+
+    pub fn get(&self, x_idx: u32, y_idx: u32) -> bool {
+        let index = self.index(x_idx, y_idx);
+        (self.bits[index / 64] & (1 << (index % 64))) != 0
+    }
+
+    pub fn set(&mut self, x_idx: u32, y_idx: u32) {
+        let index = self.index(x_idx, y_idx);
+        self.bits[index / 64] |= 1 << (index % 64);
+    }
+
+    pub fn clear(&mut self, x_idx: u32, y_idx: u32) {
+        let index = self.index(x_idx, y_idx);
+        self.bits[index / 64] &= !(1 << (index % 64));
+    }
+
+    /// Number of dirty tiles.
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    /// Iterates over the `(x_idx, y_idx)` of every dirty tile in row-major order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let columns = self.columns;
+        (0..self.rows)
+            .flat_map(move |y_idx| (0..columns).map(move |x_idx| (x_idx, y_idx)))
+            .filter(move |&(x_idx, y_idx)| self.get(x_idx, y_idx))
+    }
+}
+
+impl std::fmt::Display for DirtyMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for y_idx in 0..self.rows {
+            f.write_str("0b")?;
+            for x_idx in 0..self.columns {
+                f.write_str(if self.get(x_idx, y_idx) { "1" } else { "0" })?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// An axis-aligned rectangle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The column past the right edge, saturating at `u32::MAX`.
+    pub fn right(&self) -> u32 {
+        self.x.saturating_add(self.width)
+    }
+
+    /// The row past the bottom edge, saturating at `u32::MAX`.
+    pub fn bottom(&self) -> u32 {
+        self.y.saturating_add(self.height)
+    }
+
+    pub fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns `true` if the rectangles share at least one pixel.
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.right()
+            && other.x < self.right()
+            && self.y < other.bottom()
+            && other.y < self.bottom()
+    }
+
+    pub fn contains(&self, other: &Rect) -> bool {
+        self.x <= other.x
+            && self.y <= other.y
+            && other.right() <= self.right()
+            && other.bottom() <= self.bottom()
+    }
+
+    /// The smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+}
+
 pub struct PartialFrame<P: image::Pixel> {
     pub x: u32,
     pub y: u32,
     pub image: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
 }
 
+/// Copies `src` of the previous frame to `(x, y)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CopyRect {
+    pub src: Rect,
+    pub x: u32,
+    pub y: u32,
+}
+
 pub enum Frame<P: image::Pixel> {
     KeyFrame(image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>),
     PartialFrame(Vec<PartialFrame<P>>),
+    /// Copies within the previous frame, followed by partial frames drawn on
+    /// top. Every copy reads from the previous frame as it was before any of
+    /// them were applied.
+    CopyFrame(Vec<CopyRect>, Vec<PartialFrame<P>>),
+    /// Nothing changed since the previous frame.
+    Unchanged,
+}
+
+/// Applies `copies` to `image`, reading every source before writing.
+pub(crate) fn apply_copies<P: 'static + image::Pixel>(
+    image: &mut image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    copies: &[CopyRect],
+) {
+    let sources: Vec<_> = copies
+        .iter()
+        .map(|copy| {
+            image::imageops::crop_imm(
+                image,
+                copy.src.x,
+                copy.src.y,
+                copy.src.width,
+                copy.src.height,
+            )
+            .to_image()
+        })
+        .collect();
+    for (copy, source) in copies.iter().zip(sources) {
+        image::imageops::replace(image, &source, copy.x, copy.y);
+    }
+}
+
+/// Tiles that differ from the reference, and optionally the bounds of the
+/// changed pixels within each tile.
+struct Changes {
+    dirty: DirtyMap,
+    bounds: Vec<Option<Rect>>,
+    /// Number of changed pixels, if `tight` counted them.
+    changed_pixels: Option<u64>,
+    /// Hashes of every tile of the new frame, in `hash_tiles` mode.
+    hashes: Vec<u64>,
+}
+
+impl Changes {
+    fn all(layout: &TileLayout) -> Self {
+        let mut dirty = DirtyMap::new(layout.columns, layout.rows);
+        for y_idx in 0..layout.rows {
+            for x_idx in 0..layout.columns {
+                dirty.set(x_idx, y_idx);
+            }
+        }
+        Changes {
+            dirty,
+            bounds: Vec::new(),
+            changed_pixels: None,
+            hashes: Vec::new(),
+        }
+    }
+}
+
+/// What a single push compares: the damaged tiles, if known, and the masks of
+/// the ignore regions not reported this time.
+struct Scope<'a> {
+    damaged: Option<DirtyMap>,
+    masks: Masks<'a>,
+}
+
+/// Tile hashes of the reference and the layout they were taken with.
+struct TileHashes {
+    layout: TileLayout,
+    hashes: Vec<u64>,
+}
+
+fn tile_hashes<P>(
+    layout: &TileLayout,
+    frame: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+) -> Vec<u64>
+where
+    P: 'static + image::Pixel + Send + Sync,
+    <P as image::Pixel>::Subpixel: Send + Sync,
+{
+    #[cfg(feature = "rayon")]
+    let rows = (0..layout.rows).into_par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let rows = 0..layout.rows;
+    let bands: Vec<Vec<u64>> = rows
+        .map(|y_idx| {
+            (0..layout.columns)
+                .map(|x_idx| hash::tile_hash(frame, &layout.rect(Rect::new(x_idx, y_idx, 1, 1))))
+                .collect()
+        })
+        .collect();
+    bands.into_iter().flatten().collect()
 }
 
 impl<P: 'static + image::Pixel> FrameContext<P>
 where
-    P: image::Pixel + std::cmp::PartialEq,
+    P: image::Pixel + std::cmp::PartialEq + Send + Sync,
+    <P as image::Pixel>::Subpixel: Send + Sync,
 {
+    /// Compares one tile against the reference, stopping at the first change
+    /// unless `tight` asks for the bounds and number of the changed pixels.
+    fn diff_tile(
+        &self,
+        frame: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        reference: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        tile: &Rect,
+        masks: &Masks,
+    ) -> Option<(Rect, u64)> {
+        let coverage = masks.coverage(tile);
+        if coverage == Coverage::Full {
+            return None;
+        }
+        let channels = P::CHANNEL_COUNT as usize;
+        let stride = self.width as usize * channels;
+        let offset = |x: u32, y: u32| y as usize * stride + x as usize * channels;
+        let (new, old) = (frame.as_raw(), reference.as_raw());
+        let pixel_differs = |x: u32, y: u32| {
+            if coverage == Coverage::Partial && masks.contains(x, y) {
+                return false;
+            }
+            let at = offset(x, y);
+            self.compare.differs(
+                P::from_slice(&new[at..at + channels]),
+                P::from_slice(&old[at..at + channels]),
+            )
+        };
+        let row_differs = |y: u32| {
+            if self.compare.is_exact() && coverage == Coverage::None {
+                let range = offset(tile.x, y)..offset(tile.right(), y);
+                new[range.clone()] != old[range]
+            } else {
+                (tile.x..tile.right()).any(|x| pixel_differs(x, y))
+            }
+        };
+
+        let top = (tile.y..tile.bottom()).find(|&y| row_differs(y))?;
+        if !self.tight {
+            return Some((*tile, 0));
+        }
+        let bottom = (top..tile.bottom())
+            .rev()
+            .find(|&y| row_differs(y))
+            .unwrap_or(top);
+        let (mut left, mut right, mut changed) = (tile.right(), tile.x, 0);
+        for y in (top..=bottom).filter(|&y| row_differs(y)) {
+            for x in (tile.x..tile.right()).filter(|&x| pixel_differs(x, y)) {
+                left = left.min(x);
+                right = right.max(x + 1);
+                changed += 1;
+            }
+        }
+        Some((
+            Rect::new(left, top, right - left, bottom + 1 - top),
+            changed,
+        ))
+    }
+
+    /// Compares `frame` against the reference. Only the damaged tiles are
+    /// compared when `scope` has them; all others are taken as unchanged.
+    fn diff(
+        &self,
+        layout: &TileLayout,
+        frame: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        scope: &Scope,
+    ) -> Changes {
+        if self.hash_tiles {
+            return self.diff_hashes(layout, frame, scope);
+        }
+        let reference = match &self.frame {
+            Some(reference) => reference,
+            None => return Changes::all(layout),
+        };
+        #[cfg(feature = "rayon")]
+        let rows = (0..layout.rows).into_par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let rows = 0..layout.rows;
+        let bands: Vec<Vec<Option<(Rect, u64)>>> = rows
+            .map(|y_idx| {
+                (0..layout.columns)
+                    .map(|x_idx| {
+                        if let Some(damaged) = &scope.damaged {
+                            if !damaged.get(x_idx, y_idx) {
+                                return None;
+                            }
+                        }
+                        let tile = layout.rect(Rect::new(x_idx, y_idx, 1, 1));
+                        self.diff_tile(frame, reference, &tile, &scope.masks)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut dirty = DirtyMap::new(layout.columns, layout.rows);
+        for (y_idx, band) in bands.iter().enumerate() {
+            for (x_idx, tile) in band.iter().enumerate() {
+                if tile.is_some() {
+                    dirty.set(x_idx as u32, y_idx as u32);
+                }
+            }
+        }
+        let (bounds, changed_pixels) = if self.tight {
+            let tiles: Vec<_> = bands.into_iter().flatten().collect();
+            let changed = tiles.iter().flatten().map(|(_, changed)| changed).sum();
+            let bounds = tiles.into_iter().map(|tile| tile.map(|(rect, _)| rect));
+            (bounds.collect(), Some(changed))
+        } else {
+            (Vec::new(), None)
+        };
+        Changes {
+            dirty,
+            bounds,
+            changed_pixels,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn diff_hashes(
+        &self,
+        layout: &TileLayout,
+        frame: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        scope: &Scope,
+    ) -> Changes {
+        let previous = match (&self.hashes, &self.frame) {
+            (Some(previous), _) if previous.layout == *layout => Some(previous.hashes.clone()),
+            (_, Some(reference)) => Some(tile_hashes(layout, reference)),
+            _ => None,
+        };
+        let mut hashes = match (&previous, &scope.damaged) {
+            (Some(previous), Some(damaged)) => {
+                let mut hashes = previous.clone();
+                for (x_idx, y_idx) in damaged.iter() {
+                    let tile = layout.rect(Rect::new(x_idx, y_idx, 1, 1));
+                    hashes[(y_idx * layout.columns + x_idx) as usize] =
+                        hash::tile_hash(frame, &tile);
+                }
+                hashes
+            }
+            _ => tile_hashes(layout, frame),
+        };
+        if let Some(previous) = previous.as_ref().filter(|_| !scope.masks.is_empty()) {
+            for (index, hash) in hashes.iter_mut().enumerate() {
+                let index = index as u32;
+                let tile = layout.rect(Rect::new(
+                    index % layout.columns,
+                    index / layout.columns,
+                    1,
+                    1,
+                ));
+                if scope.masks.coverage(&tile) == Coverage::Full {
+                    *hash = previous[index as usize];
+                }
+            }
+        }
+        let mut changes = match previous {
+            Some(previous) => {
+                let mut dirty = DirtyMap::new(layout.columns, layout.rows);
+                for (index, (new, old)) in hashes.iter().zip(&previous).enumerate() {
+                    if new != old {
+                        let index = index as u32;
+                        dirty.set(index % layout.columns, index / layout.columns);
+                    }
+                }
+                Changes {
+                    dirty,
+                    bounds: Vec::new(),
+                    changed_pixels: None,
+                    hashes: Vec::new(),
+                }
+            }
+            None => Changes::all(layout),
+        };
+        changes.hashes = hashes;
+        changes
+    }
+
+    /// Pixel rectangles to emit for `changes`.
+    fn rects(&self, layout: &TileLayout, changes: &Changes) -> Vec<Rect> {
+        let dirty = &changes.dirty;
+        let tiles: Vec<Rect> = match (self.grid, &self.merge) {
+            (
+                Grid::QuadTree {
+                    min_dirty_ratio, ..
+                },
+                _,
+            ) => region::quadtree(dirty, min_dirty_ratio),
+            (_, Some(merge)) => region::merge(dirty, merge),
+            _ => dirty
+                .iter()
+                .map(|(x_idx, y_idx)| Rect::new(x_idx, y_idx, 1, 1))
+                .collect(),
+        };
+        tiles
+            .into_iter()
+            .map(|tiles| {
+                // Hash mode and `Changes::all` keep no bounds.
+                if self.tight && !changes.bounds.is_empty() {
+                    (tiles.y..tiles.bottom())
+                        .flat_map(|y_idx| (tiles.x..tiles.right()).map(move |x_idx| (x_idx, y_idx)))
+                        .filter_map(|(x_idx, y_idx)| {
+                            changes.bounds[(y_idx * layout.columns + x_idx) as usize]
+                        })
+                        .reduce(|a, b| a.union(&b))
+                        .unwrap_or_else(|| layout.rect(tiles))
+                } else {
+                    layout.rect(tiles)
+                }
+            })
+            .collect()
+    }
+
     pub fn push(
         &mut self,
         timestamp: &Duration,
         frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
     ) -> Frame<P> {
-        if self.current < self.limits {
-            self.current += 1;
-            let mut frames = Vec::new();
-            const BLOCK_SIZE: usize = 16;
-            const BIT_VALUE: u16 = 1;
-            #[inline(always)]
-            fn print_bits_ln(val: &u16) -> String {
-                format!("{:#018b}\n", val)
-            }
-            #[inline(always)]
-            fn round_to_size<const N: usize>(len: usize) -> usize {
-                ((len + (N - 1)) & !(N - 1)) / N
-            }
-            let res = imageproc::utils::pixel_diffs(&frame, &self.frame, |p, q| p != q);
-            let mut bit_map = [0; BLOCK_SIZE];
-            let y_base = round_to_size::<BLOCK_SIZE>(self.height as usize);
-            let x_base = round_to_size::<BLOCK_SIZE>(self.width as usize);
-            for diff in &res {
-                let bit = BIT_VALUE << ((BLOCK_SIZE - 1) - (diff.x as usize / x_base));
-                bit_map[diff.y as usize / y_base] |= bit;
-            }
-
-            let mut dump = format!(
-                "{}x{}------------------------------------------------------\n",
-                self.width, self.height
+        self.push_inner(timestamp, frame, None)
+    }
+
+    /// Like `push`, but only compares the tiles touched by `damage`, e.g. the
+    /// damage rectangles reported by a compositor or capture API. Changes
+    /// outside of them are not sent until a later damage rectangle covers them.
+    pub fn push_with_damage(
+        &mut self,
+        timestamp: &Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        damage: &[Rect],
+    ) -> Frame<P> {
+        self.push_inner(timestamp, frame, Some(damage))
+    }
+
+    /// Statistics of the last push.
+    pub fn stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    /// Like `push`, but also returns the statistics of this push, which
+    /// `stats` only holds until the next one.
+    pub fn push_with_stats(
+        &mut self,
+        timestamp: &Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    ) -> (Frame<P>, FrameStats) {
+        let frame = self.push(timestamp, frame);
+        (frame, self.stats.clone())
+    }
+
+    /// Makes the next `push` return a `Frame::KeyFrame`, e.g. when a viewer
+    /// joins.
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    /// Returns the reference frame as a `Frame::KeyFrame` right away and
+    /// restarts the key frame cycle. Later pushes are diffed against it, so it
+    /// can be sent to a new viewer in place of the next key frame.
+    ///
+    /// Returns `None` in `hash_tiles` mode, where no reference is kept; use
+    /// `request_keyframe` there.
+    pub fn keyframe(&mut self) -> Option<Frame<P>> {
+        let reference = self.frame.clone()?;
+        self.current = 0;
+        self.keyframe_at = self.timestamp;
+        self.keyframe_requested = false;
+        Some(Frame::KeyFrame(reference))
+    }
+
+    fn push_inner(
+        &mut self,
+        timestamp: &Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        damage: Option<&[Rect]>,
+    ) -> Frame<P> {
+        // A new resolution cannot be diffed against the old reference; start
+        // over with a key frame, whose dimensions tell the receiver to reallocate.
+        let resized = frame.dimensions() != (self.width, self.height);
+        if resized {
+            trace!(
+                "resized {}x{} -> {}x{}",
+                self.width,
+                self.height,
+                frame.width(),
+                frame.height()
             );
-            for bits in bit_map.iter() {
-                dump.push_str(&print_bits_ln(bits));
-            }
-            dump.push_str("------------------------------------------------------");
-            trace!("{}", dump);
-            for y_idx in 0..bit_map.len() {
-                for x_idx in 0..BLOCK_SIZE {
-                    let bits = bit_map[y_idx].reverse_bits();
-                    if bits == 0 {
-                        continue;
-                    }
-                    if (bits & (BIT_VALUE << x_idx)) != 0 {
-                        let x = (x_idx * x_base) as u32;
-                        let y = (y_idx * y_base) as u32;
-                        let width = x_base as u32;
-                        let height = y_base as u32;
-                        let sub_image = image::imageops::crop_imm(&frame, x, y, width, height);
-                        let image = sub_image.to_image();
-                        frames.push(PartialFrame {
-                            x,
-                            y,
-                            image: image.clone(),
-                        });
+            self.width = frame.width();
+            self.height = frame.height();
+        }
+        let expired = self
+            .keyframe_policy
+            .expired(timestamp.saturating_sub(self.keyframe_at));
+        let layout = TileLayout::new(self.width, self.height, self.grid);
+        let reason = if resized {
+            Some(KeyframeReason::Resized)
+        } else if self.keyframe_requested {
+            Some(KeyframeReason::Requested)
+        } else if self.current >= self.limits {
+            Some(KeyframeReason::Limits)
+        } else if expired && self.count_unchanged {
+            Some(KeyframeReason::Interval)
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            let total_tiles = (layout.columns * layout.rows) as usize;
+            self.stats = FrameStats {
+                dirty_tiles: total_tiles,
+                total_tiles,
+                dirty_area: self.width as u64 * self.height as u64,
+                dirty_ratio: 1.0,
+                ..FrameStats::default()
+            };
+            return self.key_frame(timestamp, frame, reason);
+        }
+        let started = Instant::now();
+        self.pushes = self.pushes.wrapping_add(1);
+        let scope = Scope {
+            damaged: damage.map(|damage| layout.touched(damage)),
+            masks: Masks::active(&self.ignore, self.pushes),
+        };
+        let mut changes = self.diff(&layout, &frame, &scope);
+        // Where the frame differs from the reference before any copies.
+        let changed = changes.dirty.clone();
+        let mut copies = Vec::new();
+        if let Some(reference) = self.frame.as_ref().filter(|_| !self.hash_tiles) {
+            if let Some(scroll) = &self.scroll {
+                let area = changes
+                    .dirty
+                    .iter()
+                    .map(|(x_idx, y_idx)| Rect::new(x_idx, y_idx, 1, 1))
+                    .reduce(|a, b| a.union(&b))
+                    .map(|tiles| layout.rect(tiles));
+                if let Some(area) = area {
+                    copies.extend(motion::detect_scroll(reference, &frame, area, scroll));
+                }
+            }
+            if self.motion {
+                let mut dirty = changes.dirty.clone();
+                for copy in &copies {
+                    let dst = Rect::new(copy.x, copy.y, copy.src.width, copy.src.height);
+                    for (x_idx, y_idx) in changes.dirty.iter() {
+                        if dst.contains(&layout.rect(Rect::new(x_idx, y_idx, 1, 1))) {
+                            dirty.clear(x_idx, y_idx);
+                        }
                     }
                 }
+                let index = match self.motion_index.take() {
+                    Some(index) if index.layout() == &layout => index,
+                    _ => MotionIndex::new(reference, &layout),
+                };
+                copies.extend(motion::detect_moves(
+                    reference, &frame, &layout, &dirty, &index,
+                ));
+                self.motion_index = Some(index);
+            }
+        }
+        if let Some(reference) = self.frame.as_mut().filter(|_| !copies.is_empty()) {
+            // Predict the frame from the copies, then send what is left.
+            apply_copies(reference, &copies);
+            changes = self.diff(&layout, &frame, &scope);
+        }
+        trace!(
+            "{}x{}------------------------------------------------------\n{}------------------------------------------------------",
+            self.width,
+            self.height,
+            changes.dirty
+        );
+        let rects = self.rects(&layout, &changes);
+
+        let dirty_ratio =
+            changes.dirty.count() as f32 / (layout.columns as f32 * layout.rows as f32).max(1.0);
+        let payload = rects
+            .iter()
+            .map(|rect| rect.area() as usize * std::mem::size_of::<P>())
+            .sum();
+        let unchanged = rects.is_empty() && copies.is_empty();
+        let regions: Vec<Rect> = changes
+            .dirty
+            .iter()
+            .map(|(x_idx, y_idx)| {
+                changes
+                    .bounds
+                    .get((y_idx * layout.columns + x_idx) as usize)
+                    .copied()
+                    .flatten()
+                    .unwrap_or_else(|| layout.rect(Rect::new(x_idx, y_idx, 1, 1)))
+            })
+            .collect();
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.record(self.width, self.height, &regions);
+        }
+        let dirty_area = regions.iter().map(Rect::area).sum();
+        self.stats = FrameStats {
+            dirty_tiles: changes.dirty.count(),
+            total_tiles: (layout.columns * layout.rows) as usize,
+            dirty_area,
+            changed_pixels: changes.changed_pixels,
+            dirty_ratio: dirty_area as f32 / (self.width as f32 * self.height as f32).max(1.0),
+            bytes: payload,
+            diff_time: started.elapsed(),
+            ..FrameStats::default()
+        };
+        let reason = if expired && !unchanged {
+            Some(KeyframeReason::Interval)
+        } else {
+            self.keyframe_policy.triggered(dirty_ratio, payload)
+        };
+        if let Some(reason) = reason {
+            trace!(
+                "key frame: {:.2} of tiles dirty, {} bytes of partial frames",
+                dirty_ratio,
+                payload
+            );
+            return self.key_frame(timestamp, frame, reason);
+        }
+        let started = Instant::now();
+
+        if !unchanged || self.count_unchanged {
+            self.current += 1;
+        }
+
+        #[cfg(feature = "rayon")]
+        let rects = rects.into_par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let rects = rects.into_iter();
+        let frames: Vec<PartialFrame<P>> = rects
+            .map(|rect| {
+                let sub_image =
+                    image::imageops::crop_imm(&frame, rect.x, rect.y, rect.width, rect.height);
+                PartialFrame {
+                    x: rect.x,
+                    y: rect.y,
+                    image: sub_image.to_image(),
+                }
+            })
+            .collect();
+        self.timestamp = *timestamp;
+        // Changes below the comparator's threshold, outside the damage or
+        // under an ignore mask were not sent and must not leak into the
+        // reference.
+        let lossy = !self.compare.is_exact() || damage.is_some() || !scope.masks.is_empty();
+        if self.hash_tiles {
+            self.frame = None;
+            self.motion_index = None;
+            self.hashes = Some(TileHashes {
+                layout,
+                hashes: changes.hashes,
+            });
+        } else {
+            self.hashes = None;
+            if let Some(reference) = self.frame.as_mut().filter(|_| lossy) {
+                // Keep the reference in sync with what the receiver has.
+                for partial in &frames {
+                    image::imageops::replace(reference, &partial.image, partial.x, partial.y);
+                }
+            } else {
+                self.frame = Some(frame);
+            }
+        }
+        if !self.motion {
+            self.motion_index = None;
+        }
+        if let (Some(index), Some(reference)) = (&mut self.motion_index, &self.frame) {
+            // Exact pushes only change the dirty tiles, lossy ones only what
+            // was copied or sent.
+            let mut changed = changed;
+            let sent: Vec<Rect> = copies
+                .iter()
+                .map(|copy| Rect::new(copy.x, copy.y, copy.src.width, copy.src.height))
+                .chain(frames.iter().map(|partial| {
+                    let (width, height) = partial.image.dimensions();
+                    Rect::new(partial.x, partial.y, width, height)
+                }))
+                .collect();
+            for (x_idx, y_idx) in layout.touched(&sent).iter() {
+                changed.set(x_idx, y_idx);
             }
-            self.timestamp = timestamp.clone();
-            self.frame = frame.clone();
+            index.update(reference, &changed);
+        }
+        self.stats.crop_time = started.elapsed();
+        if unchanged {
+            Frame::Unchanged
+        } else if copies.is_empty() {
             Frame::PartialFrame(frames)
         } else {
-            self.current = 0;
-            self.timestamp = timestamp.clone();
-            self.frame = frame.clone();
-            Frame::KeyFrame(frame)
+            Frame::CopyFrame(copies, frames)
         }
     }
+
+    fn key_frame(
+        &mut self,
+        timestamp: &Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        reason: KeyframeReason,
+    ) -> Frame<P> {
+        let started = Instant::now();
+        self.current = 0;
+        self.timestamp = *timestamp;
+        self.keyframe_at = *timestamp;
+        self.keyframe_requested = false;
+        self.motion_index = None;
+        if self.hash_tiles {
+            let layout = TileLayout::new(self.width, self.height, self.grid);
+            self.hashes = Some(TileHashes {
+                hashes: tile_hashes(&layout, &frame),
+                layout,
+            });
+            self.frame = None;
+        } else {
+            self.hashes = None;
+            // The only copy a push makes: reuse the old reference's buffer
+            // when the size allows instead of allocating a new one.
+            match self.frame.as_mut() {
+                Some(reference) if reference.dimensions() == frame.dimensions() => {
+                    reference.copy_from_slice(&frame);
+                }
+                _ => self.frame = Some(frame.clone()),
+            }
+        }
+        self.stats.bytes = frame.len() * std::mem::size_of::<<P as image::Pixel>::Subpixel>();
+        self.stats.crop_time = started.elapsed();
+        self.stats.keyframe = Some(reason);
+        Frame::KeyFrame(frame)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::FrameContext;
+    use crate::{
+        Comparator, CopyRect, FrameContext, Grid, Heatmap, Ignore, KeyframePolicy, KeyframeReason,
+        Mask, Merge, Rect, Scroll,
+    };
     use std::time::Duration;
 
     #[test]
@@ -132,6 +1071,8 @@ mod tests {
             crate::Frame::KeyFrame(frame) => {
                 println!("{:?}", frame);
             }
+            crate::Frame::CopyFrame(..) => panic!("unexpected copy frame"),
+            crate::Frame::Unchanged => panic!("unexpected unchanged frame"),
             crate::Frame::PartialFrame(frames) => {
                 assert_eq!(frames.len(), 5);
                 for frame in frames {
@@ -151,4 +1092,654 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn custom_grid() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(640, 360));
+        ctx.grid = Grid::Count {
+            columns: 64,
+            rows: 36,
+        };
+
+        let mut img = image::RgbImage::new(640, 360);
+        img.put_pixel(123, 45, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::KeyFrame(_) => panic!("unexpected key frame"),
+            crate::Frame::CopyFrame(..) => panic!("unexpected copy frame"),
+            crate::Frame::Unchanged => panic!("unexpected unchanged frame"),
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (120, 40));
+                assert_eq!(frames[0].image.dimensions(), (10, 10));
+            }
+        }
+    }
+
+    #[test]
+    fn uneven_grid() {
+        let layout = crate::TileLayout::new(
+            100,
+            3840,
+            Grid::Count {
+                columns: 30,
+                rows: 100,
+            },
+        );
+        assert_eq!((layout.columns, layout.rows), (30, 100));
+        let mut x = 0;
+        for x_idx in 0..layout.columns {
+            let tile = layout.rect(Rect::new(x_idx, 0, 1, 1));
+            assert_eq!(tile.x, x);
+            assert!(tile.width == 3 || tile.width == 4);
+            assert!((tile.x..tile.right()).all(|x| layout.column(x) == x_idx));
+            x = tile.right();
+        }
+        assert_eq!(x, 100);
+        assert_eq!(layout.rect(Rect::new(0, 99, 1, 1)).bottom(), 3840);
+
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(100, 10));
+        ctx.grid = Grid::Count {
+            columns: 30,
+            rows: 1,
+        };
+        let mut img = image::RgbImage::new(100, 10);
+        img.put_pixel(99, 5, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (96, 0));
+                assert_eq!(frames[0].image.dimensions(), (4, 10));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn fixed_tile_size() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(200, 100));
+        ctx.grid = Grid::TileSize {
+            width: 64,
+            height: 64,
+        };
+
+        let mut img = image::RgbImage::new(200, 100);
+        img.put_pixel(10, 10, image::Rgb([255, 255, 255]));
+        img.put_pixel(199, 99, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::KeyFrame(_) => panic!("unexpected key frame"),
+            crate::Frame::CopyFrame(..) => panic!("unexpected copy frame"),
+            crate::Frame::Unchanged => panic!("unexpected unchanged frame"),
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 2);
+                assert_eq!((frames[0].x, frames[0].y), (0, 0));
+                assert_eq!(frames[0].image.dimensions(), (64, 64));
+                assert_eq!((frames[1].x, frames[1].y), (192, 64));
+                assert_eq!(frames[1].image.dimensions(), (8, 36));
+            }
+        }
+    }
+
+    #[test]
+    fn tolerance() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.compare = Comparator::Channel(2.0);
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(0, 0, image::Rgb([2, 0, 0]));
+        match ctx.push(&Duration::from_secs(2), img.clone()) {
+            crate::Frame::Unchanged => {}
+            _ => panic!("expected unchanged frame"),
+        }
+
+        // The reference still holds the old pixel, so drift is caught once it
+        // crosses the threshold.
+        img.put_pixel(0, 0, image::Rgb([3, 0, 0]));
+        match ctx.push(&Duration::from_secs(3), img) {
+            crate::Frame::PartialFrame(frames) => assert_eq!(frames.len(), 1),
+            _ => panic!("expected partial frame"),
+        }
+        assert_eq!(
+            ctx.frame.as_ref().unwrap().get_pixel(0, 0),
+            &image::Rgb([3, 0, 0])
+        );
+    }
+
+    #[test]
+    fn resize() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.current = 3;
+
+        match ctx.push(&Duration::from_secs(2), image::RgbImage::new(128, 32)) {
+            crate::Frame::KeyFrame(frame) => assert_eq!(frame.dimensions(), (128, 32)),
+            _ => panic!("expected key frame"),
+        }
+        assert_eq!((ctx.width, ctx.height), (128, 32));
+        assert_eq!(ctx.current, 0);
+
+        match ctx.push(&Duration::from_secs(3), image::RgbImage::new(128, 32)) {
+            crate::Frame::Unchanged => {}
+            _ => panic!("expected unchanged frame"),
+        }
+    }
+
+    #[test]
+    fn merged_rects() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::TileSize {
+            width: 8,
+            height: 8,
+        };
+        ctx.merge = Some(Merge::default());
+
+        let mut img = image::RgbImage::new(64, 64);
+        for y in 10..30 {
+            for x in 4..20 {
+                img.put_pixel(x, y, image::Rgb([255, 255, 255]));
+            }
+        }
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (0, 8));
+                assert_eq!(frames[0].image.dimensions(), (24, 24));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn tight_bounds() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(640, 360));
+        ctx.tight = true;
+
+        let mut img = image::RgbImage::new(640, 360);
+        for y in 93..111 {
+            img.put_pixel(50, y, image::Rgb([255, 255, 255]));
+            img.put_pixel(51, y, image::Rgb([255, 255, 255]));
+        }
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (50, 93));
+                assert_eq!(frames[0].image.dimensions(), (2, 18));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn quadtree() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::QuadTree {
+            min_size: 8,
+            min_dirty_ratio: 0.75,
+        };
+
+        let mut img = image::RgbImage::new(64, 64);
+        for y in 0..32 {
+            for x in 0..24 {
+                img.put_pixel(x, y, image::Rgb([255, 255, 255]));
+            }
+        }
+        img.put_pixel(60, 60, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => {
+                let rects: Vec<_> = frames
+                    .iter()
+                    .map(|frame| (frame.x, frame.y, frame.image.width(), frame.image.height()))
+                    .collect();
+                assert_eq!(rects, vec![(0, 0, 32, 32), (56, 56, 8, 8)]);
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn scroll() {
+        let page = image::RgbImage::from_fn(64, 256, |x, y| {
+            image::Rgb([(x * 3) as u8, (y * 5 % 251) as u8, (x ^ y) as u8])
+        });
+        let view = |offset| image::imageops::crop_imm(&page, 0, offset, 64, 128).to_image();
+
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, view(0));
+        ctx.scroll = Some(Scroll::default());
+
+        match ctx.push(&Duration::from_secs(2), view(24)) {
+            crate::Frame::CopyFrame(copies, frames) => {
+                assert_eq!(
+                    copies,
+                    vec![CopyRect {
+                        src: Rect::new(0, 24, 64, 104),
+                        x: 0,
+                        y: 0,
+                    }]
+                );
+                // Only the tiles over the newly exposed strip are sent.
+                assert!(!frames.is_empty());
+                assert!(frames
+                    .iter()
+                    .all(|frame| frame.y + frame.image.height() > 104));
+            }
+            _ => panic!("expected copy frame"),
+        }
+        assert_eq!(ctx.frame, Some(view(24)));
+    }
+
+    #[test]
+    fn moved_window() {
+        let window =
+            image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 200]));
+        let mut before = image::RgbImage::new(128, 128);
+        image::imageops::replace(&mut before, &window, 10, 10);
+        let mut after = image::RgbImage::new(128, 128);
+        image::imageops::replace(&mut after, &window, 64, 80);
+
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, before);
+        ctx.grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+        ctx.motion = true;
+
+        match ctx.push(&Duration::from_secs(2), after.clone()) {
+            crate::Frame::CopyFrame(copies, frames) => {
+                assert_eq!(
+                    copies,
+                    vec![CopyRect {
+                        src: Rect::new(10, 10, 32, 32),
+                        x: 64,
+                        y: 80,
+                    }]
+                );
+                // The vacated area is still sent as pixels.
+                assert!(!frames.is_empty());
+                assert!(frames.iter().all(|frame| frame.y < 48));
+            }
+            _ => panic!("expected copy frame"),
+        }
+        assert_eq!(ctx.frame, Some(after.clone()));
+
+        // The second drag is found through the index updated by the first.
+        // The copy takes the matching background along to fill the dirty tiles.
+        let mut again = image::RgbImage::new(128, 128);
+        image::imageops::replace(&mut again, &window, 20, 70);
+        match ctx.push(&Duration::from_secs(3), again) {
+            crate::Frame::CopyFrame(copies, frames) => {
+                assert_eq!(
+                    copies,
+                    vec![CopyRect {
+                        src: Rect::new(60, 74, 48, 48),
+                        x: 16,
+                        y: 64,
+                    }]
+                );
+                assert!(frames.iter().all(|frame| frame.x >= 64));
+            }
+            _ => panic!("expected copy frame"),
+        }
+    }
+
+    #[test]
+    fn tight_bounds_lossy() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::TileSize {
+            width: 32,
+            height: 32,
+        };
+        ctx.compare = Comparator::Channel(8.0);
+        ctx.tight = true;
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(3, 40, image::Rgb([4, 4, 4]));
+        img.put_pixel(5, 41, image::Rgb([255, 0, 0]));
+        img.put_pixel(9, 50, image::Rgb([0, 255, 0]));
+        img.put_pixel(2, 50, image::Rgb([0, 0, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (2, 41));
+                assert_eq!(frames[0].image.dimensions(), (8, 10));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn hash_tiles() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.hash_tiles = true;
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(20, 30, image::Rgb([1, 0, 0]));
+        match ctx.push(&Duration::from_secs(2), img.clone()) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (20, 28));
+            }
+            _ => panic!("expected partial frame"),
+        }
+        assert!(ctx.frame.is_none());
+
+        match ctx.push(&Duration::from_secs(3), img.clone()) {
+            crate::Frame::Unchanged => {}
+            _ => panic!("expected unchanged frame"),
+        }
+
+        img.put_pixel(63, 63, image::Rgb([0, 0, 1]));
+        match ctx.push(&Duration::from_secs(4), img) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (60, 60));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn damage_hints() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(5, 5, image::Rgb([255, 255, 255]));
+        img.put_pixel(40, 40, image::Rgb([255, 255, 255]));
+        match ctx.push_with_damage(&Duration::from_secs(2), img, &[Rect::new(48, 0, 16, 16)]) {
+            crate::Frame::Unchanged => {}
+            _ => panic!("expected unchanged frame"),
+        }
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(40, 40, image::Rgb([255, 255, 255]));
+        match ctx.push_with_damage(&Duration::from_secs(3), img, &[Rect::new(30, 30, 20, 20)]) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (32, 32));
+            }
+            _ => panic!("expected partial frame"),
+        }
+
+        // Rectangles reaching past `u32::MAX` are clipped, not overflowed.
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(60, 5, image::Rgb([255, 255, 255]));
+        match ctx.push_with_damage(
+            &Duration::from_secs(4),
+            img,
+            &[Rect::new(4, 4, u32::MAX, 2)],
+        ) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (48, 0));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn ignore_regions() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+        let clock = Rect::new(40, 0, 24, 8);
+        ctx.ignore.push(Ignore {
+            mask: Mask::Rect(clock),
+            interval: 3,
+        });
+
+        let tick = |n: u8| {
+            let mut img = image::RgbImage::new(64, 64);
+            img.put_pixel(50, 4, image::Rgb([n, n, n]));
+            img
+        };
+        for n in 1..3 {
+            match ctx.push(&Duration::from_secs(n as u64 + 1), tick(n)) {
+                crate::Frame::Unchanged => {}
+                _ => panic!("expected unchanged frame"),
+            }
+        }
+        assert_eq!(
+            ctx.frame.as_ref().unwrap().get_pixel(50, 4),
+            &image::Rgb([0, 0, 0])
+        );
+
+        // Every third push reports the region again.
+        match ctx.push(&Duration::from_secs(4), tick(3)) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (48, 0));
+            }
+            _ => panic!("expected partial frame"),
+        }
+        assert_eq!(
+            ctx.frame.as_ref().unwrap().get_pixel(50, 4),
+            &image::Rgb([3, 3, 3])
+        );
+    }
+
+    #[test]
+    fn ignore_to_right_edge() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+        ctx.ignore
+            .push(Ignore::new(Mask::Rect(Rect::new(4, 4, u32::MAX, 2))));
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(60, 5, image::Rgb([255, 255, 255]));
+        img.put_pixel(60, 20, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!((frames[0].x, frames[0].y), (48, 16));
+            }
+            _ => panic!("expected partial frame"),
+        }
+    }
+
+    #[test]
+    fn adaptive_key_frame() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.keyframe_policy = KeyframePolicy {
+            max_dirty_ratio: Some(0.9),
+            ..KeyframePolicy::default()
+        };
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img) {
+            crate::Frame::PartialFrame(frames) => assert_eq!(frames.len(), 1),
+            _ => panic!("expected partial frame"),
+        }
+        assert_eq!(ctx.current, 1);
+
+        let img = image::RgbImage::from_pixel(64, 64, image::Rgb([1, 2, 3]));
+        match ctx.push(&Duration::from_secs(3), img) {
+            crate::Frame::KeyFrame(_) => {}
+            _ => panic!("expected key frame"),
+        }
+        assert_eq!(ctx.current, 0);
+
+        ctx.keyframe_policy = KeyframePolicy {
+            max_payload: Some(2 * 4 * 4 * 3),
+            ..KeyframePolicy::default()
+        };
+        let mut img = image::RgbImage::from_pixel(64, 64, image::Rgb([1, 2, 3]));
+        img.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+        img.put_pixel(63, 63, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(4), img) {
+            crate::Frame::KeyFrame(_) => {}
+            _ => panic!("expected key frame"),
+        }
+    }
+
+    #[test]
+    fn key_frame_interval() {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 100, image::RgbImage::new(64, 64));
+        ctx.keyframe_policy.max_interval = Some(Duration::from_secs(2));
+
+        let is_key =
+            |frame: crate::Frame<image::Rgb<u8>>| matches!(frame, crate::Frame::KeyFrame(_));
+        let img = image::RgbImage::new(64, 64);
+        assert!(!is_key(ctx.push(&Duration::from_millis(500), img.clone())));
+        assert!(!is_key(ctx.push(&Duration::from_millis(1900), img.clone())));
+        assert!(is_key(ctx.push(&Duration::from_millis(2000), img.clone())));
+        assert!(!is_key(ctx.push(&Duration::from_millis(3000), img.clone())));
+        assert!(is_key(ctx.push(&Duration::from_millis(4500), img)));
+    }
+
+    #[test]
+    fn requested_key_frame() {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 100, image::RgbImage::new(64, 64));
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(5, 5, image::Rgb([255, 0, 0]));
+        assert!(matches!(
+            ctx.push(&Duration::from_secs(1), img.clone()),
+            crate::Frame::PartialFrame(_)
+        ));
+
+        ctx.request_keyframe();
+        match ctx.push(&Duration::from_secs(2), img.clone()) {
+            crate::Frame::KeyFrame(key) => assert_eq!(key, img),
+            _ => panic!("expected key frame"),
+        }
+        assert!(matches!(
+            ctx.push(&Duration::from_secs(3), img.clone()),
+            crate::Frame::Unchanged
+        ));
+        assert_eq!(ctx.current, 1);
+
+        match ctx.keyframe() {
+            Some(crate::Frame::KeyFrame(key)) => assert_eq!(key, img),
+            _ => panic!("expected key frame"),
+        }
+        assert_eq!(ctx.current, 0);
+
+        ctx.hash_tiles = true;
+        ctx.push(&Duration::from_secs(4), img);
+        assert!(ctx.keyframe().is_none());
+    }
+
+    #[test]
+    fn unchanged() {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 2, image::RgbImage::new(64, 64));
+        let img = image::RgbImage::new(64, 64);
+        for n in 1..3 {
+            assert!(matches!(
+                ctx.push(&Duration::from_secs(n), img.clone()),
+                crate::Frame::Unchanged
+            ));
+        }
+        assert_eq!(ctx.current, 2);
+        assert!(matches!(
+            ctx.push(&Duration::from_secs(3), img.clone()),
+            crate::Frame::KeyFrame(_)
+        ));
+
+        ctx.count_unchanged = false;
+        ctx.keyframe_policy.max_interval = Some(Duration::from_secs(5));
+        for n in 4..20 {
+            assert!(matches!(
+                ctx.push(&Duration::from_secs(n), img.clone()),
+                crate::Frame::Unchanged
+            ));
+        }
+        assert_eq!(ctx.current, 0);
+
+        let mut img = img;
+        img.put_pixel(1, 1, image::Rgb([1, 1, 1]));
+        assert!(matches!(
+            ctx.push(&Duration::from_secs(20), img),
+            crate::Frame::KeyFrame(_)
+        ));
+    }
+
+    #[test]
+    fn stats() {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 1, image::RgbImage::new(64, 64));
+        ctx.grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+        ctx.tight = true;
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(1, 1, image::Rgb([255, 255, 255]));
+        img.put_pixel(2, 2, image::Rgb([255, 255, 255]));
+        img.put_pixel(40, 40, image::Rgb([255, 255, 255]));
+        ctx.push(&Duration::from_secs(1), img.clone());
+        let stats = ctx.stats();
+        assert_eq!((stats.dirty_tiles, stats.total_tiles), (2, 16));
+        assert_eq!(stats.dirty_area, 5);
+        assert_eq!(stats.changed_pixels, Some(3));
+        assert_eq!(stats.dirty_ratio, 5.0 / 4096.0);
+        assert_eq!(stats.bytes, 15);
+        assert_eq!(stats.keyframe, None);
+
+        let (frame, stats) = ctx.push_with_stats(&Duration::from_secs(2), img.clone());
+        assert!(matches!(frame, crate::Frame::KeyFrame(_)));
+        assert_eq!(stats.keyframe, Some(KeyframeReason::Limits));
+        assert_eq!(stats.bytes, 64 * 64 * 3);
+        assert_eq!(stats.dirty_ratio, 1.0);
+        assert_eq!(stats.changed_pixels, None);
+
+        // Without `tight` a tile stops at its first change, so nothing is counted.
+        ctx.tight = false;
+        ctx.limits = usize::MAX;
+        let mut next = img.clone();
+        next.put_pixel(3, 3, image::Rgb([255, 255, 255]));
+        let (_, stats) = ctx.push_with_stats(&Duration::from_secs(3), next);
+        assert_eq!((stats.dirty_area, stats.changed_pixels), (256, None));
+    }
+
+    #[test]
+    fn heatmap() {
+        let mut ctx = FrameContext::new(Duration::from_secs(0), 10, image::RgbImage::new(64, 64));
+        ctx.heatmap = Some(Heatmap::new(16));
+        ctx.tight = true;
+
+        for n in 1..5u8 {
+            let mut img = image::RgbImage::new(64, 64);
+            img.put_pixel(60, 2, image::Rgb([n, n, n]));
+            if n % 2 == 0 {
+                img.put_pixel(2, 60, image::Rgb([255, 255, 255]));
+            }
+            ctx.push(&Duration::from_secs(n as u64), img);
+        }
+        let heatmap = ctx.heatmap.as_ref().unwrap();
+        assert_eq!(heatmap.samples(), 4);
+        assert_eq!(heatmap.get(3, 0), 4);
+        // Drawn on the second and fourth push, erased on the third.
+        assert_eq!(heatmap.get(0, 3), 3);
+        assert_eq!(heatmap.get(1, 1), 0);
+    }
+
+    #[test]
+    fn hash_tiles_ignore_tight() {
+        let mut ctx = FrameContext::new(Duration::from_secs(1), 10, image::RgbImage::new(64, 64));
+        ctx.hash_tiles = true;
+        ctx.tight = true;
+
+        let mut img = image::RgbImage::new(64, 64);
+        img.put_pixel(1, 1, image::Rgb([255, 255, 255]));
+        match ctx.push(&Duration::from_secs(2), img.clone()) {
+            crate::Frame::PartialFrame(frames) => {
+                assert_eq!(frames.len(), 1);
+                assert_eq!(frames[0].image.dimensions(), (4, 4));
+            }
+            _ => panic!("expected partial frame"),
+        }
+
+        // Without a reference the first push after hash mode resends every tile.
+        ctx.hash_tiles = false;
+        match ctx.push(&Duration::from_secs(3), img) {
+            crate::Frame::PartialFrame(frames) => assert_eq!(frames.len(), 256),
+            _ => panic!("expected partial frame"),
+        }
+    }
 }
@@ -0,0 +1,100 @@
+use crate::Rect;
+
+/// An area of the frame, in pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mask {
+    Rect(Rect),
+    /// Every non-zero pixel is part of the mask. Pixels outside the image are not.
+    Image(image::GrayImage),
+}
+
+/// An area whose changes are not reported, or only every `interval` pushes.
+///
+/// Unreported changes are kept out of the reference, so they are still sent
+/// once the area is reported again or a tile is sent for another change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ignore {
+    pub mask: Mask,
+    /// Report changes under the mask on every `interval`-th push; `0` never does.
+    pub interval: usize,
+}
+
+impl Ignore {
+    pub fn new(mask: Mask) -> Self {
+        Ignore { mask, interval: 0 }
+    }
+}
+
+/// How much of a tile a set of masks covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coverage {
+    None,
+    Partial,
+    Full,
+}
+
+impl Mask {
+    fn contains(&self, x: u32, y: u32) -> bool {
+        match self {
+            Mask::Rect(rect) => rect.intersects(&Rect::new(x, y, 1, 1)),
+            Mask::Image(image) => {
+                x < image.width() && y < image.height() && image.get_pixel(x, y)[0] != 0
+            }
+        }
+    }
+
+    fn coverage(&self, tile: &Rect) -> Coverage {
+        match self {
+            Mask::Rect(rect) if rect.contains(tile) => Coverage::Full,
+            Mask::Rect(rect) if rect.intersects(tile) => Coverage::Partial,
+            Mask::Rect(_) => Coverage::None,
+            Mask::Image(_) => {
+                let covered = (tile.y..tile.bottom())
+                    .flat_map(|y| (tile.x..tile.right()).map(move |x| (x, y)))
+                    .filter(|&(x, y)| self.contains(x, y))
+                    .count() as u64;
+                if covered == 0 {
+                    Coverage::None
+                } else if covered == tile.area() {
+                    Coverage::Full
+                } else {
+                    Coverage::Partial
+                }
+            }
+        }
+    }
+}
+
+/// The masks of the `Ignore` regions not reported on this push.
+pub(crate) struct Masks<'a>(Vec<&'a Mask>);
+
+impl<'a> Masks<'a> {
+    pub fn active(ignore: &'a [Ignore], pushes: usize) -> Self {
+        Masks(
+            ignore
+                .iter()
+                .filter(|ignore| ignore.interval == 0 || !pushes.is_multiple_of(ignore.interval))
+                .map(|ignore| &ignore.mask)
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        self.0.iter().any(|mask| mask.contains(x, y))
+    }
+
+    pub fn coverage(&self, tile: &Rect) -> Coverage {
+        self.0
+            .iter()
+            .map(|mask| mask.coverage(tile))
+            .fold(Coverage::None, |coverage, mask| match (coverage, mask) {
+                (Coverage::Full, _) | (_, Coverage::Full) => Coverage::Full,
+                (Coverage::Partial, _) | (_, Coverage::Partial) => Coverage::Partial,
+                _ => Coverage::None,
+            })
+    }
+}
@@ -0,0 +1,635 @@
+use crate::hash::{hash_start, hash_subpixels};
+use crate::{CopyRect, DirtyMap, Rect, TileLayout};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Scroll detection settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scroll {
+    /// Largest scroll distance searched, in pixels.
+    pub max_distance: u32,
+    /// Shortest run of shifted rows (or columns) worth a copy, in pixels.
+    pub min_length: u32,
+}
+
+impl Default for Scroll {
+    fn default() -> Self {
+        Scroll {
+            max_distance: 256,
+            min_length: 16,
+        }
+    }
+}
+
+/// A run of lines in `new` found at `offset` lines further in `old`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Shift {
+    offset: i64,
+    start: usize,
+    len: usize,
+    /// Lines in the run that changed in place, i.e. what the copy saves.
+    benefit: usize,
+}
+
+/// Finds the offset whose longest run of matching lines covers the most
+/// changed lines. Lines that did not change (blank backgrounds, static
+/// borders) match at every offset and are not counted as a benefit.
+fn best_shift(old: &[u64], new: &[u64], max_distance: u32) -> Option<Shift> {
+    let n = new.len() as i64;
+    let mut best: Option<Shift> = None;
+    for distance in 1..=(max_distance as i64).min(n - 1) {
+        for offset in [distance, -distance] {
+            let mut run: Option<Shift> = None;
+            for i in 0..n {
+                let j = i + offset;
+                let matches = j >= 0 && j < n && new[i as usize] == old[j as usize];
+                if matches {
+                    let changed = (new[i as usize] != old[i as usize]) as usize;
+                    let run = run.get_or_insert(Shift {
+                        offset,
+                        start: i as usize,
+                        len: 0,
+                        benefit: 0,
+                    });
+                    run.len += 1;
+                    run.benefit += changed;
+                } else if let Some(done) = run.take() {
+                    if best.is_none_or(|best| done.benefit > best.benefit) {
+                        best = Some(done);
+                    }
+                }
+            }
+            if let Some(done) = run {
+                if best.is_none_or(|best| done.benefit > best.benefit) {
+                    best = Some(done);
+                }
+            }
+        }
+    }
+    best.filter(|best| best.benefit > 0)
+}
+
+/// Whether line `old_line` of `old` equals line `new_line` of `new` over the
+/// pixels `span` across them. Lines are rows when `vertical`, else columns.
+fn line_eq<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    vertical: bool,
+    old_line: u32,
+    new_line: u32,
+    span: Range<u32>,
+) -> bool {
+    span.into_iter().all(|across| {
+        let (old_pixel, new_pixel) = if vertical {
+            (
+                old.get_pixel(across, old_line),
+                new.get_pixel(across, new_line),
+            )
+        } else {
+            (
+                old.get_pixel(old_line, across),
+                new.get_pixel(new_line, across),
+            )
+        };
+        old_pixel.channels() == new_pixel.channels()
+    })
+}
+
+fn line_hash<P: 'static + image::Pixel>(
+    image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    vertical: bool,
+    line: u32,
+    span: Range<u32>,
+) -> u64 {
+    if vertical {
+        let channels = P::CHANNEL_COUNT as usize;
+        let start = (line as usize * image.width() as usize + span.start as usize) * channels;
+        let end = start + span.len() * channels;
+        hash_subpixels(hash_start(), &image.as_raw()[start..end])
+    } else {
+        span.fold(hash_start(), |state, y| {
+            hash_subpixels(state, image.get_pixel(line, y).channels())
+        })
+    }
+}
+
+/// Shrinks `area` past the rows and columns at its edges that did not change,
+/// such as the static borders around a scrolling pane.
+fn trim<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    area: Rect,
+) -> Rect {
+    let (mut left, mut right) = (area.x, area.right());
+    let (mut top, mut bottom) = (area.y, area.bottom());
+    while left < right && line_eq(old, new, false, left, left, top..bottom) {
+        left += 1;
+    }
+    while right > left && line_eq(old, new, false, right - 1, right - 1, top..bottom) {
+        right -= 1;
+    }
+    while top < bottom && line_eq(old, new, true, top, top, left..right) {
+        top += 1;
+    }
+    while bottom > top && line_eq(old, new, true, bottom - 1, bottom - 1, left..right) {
+        bottom -= 1;
+    }
+    Rect::new(left, top, right - left, bottom - top)
+}
+
+/// Looks for lines of `area` shifted along one axis, returning what the copy
+/// saves and the copy itself.
+///
+/// Lines are only compared over the middle half across them, away from
+/// borders and scrollbars that do not move with the content; the copy then
+/// grows across as far as the shifted content reaches.
+fn find_shift<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    area: &Rect,
+    scroll: &Scroll,
+    vertical: bool,
+) -> Option<(usize, CopyRect)> {
+    let (first_line, lines, first_across, across) = if vertical {
+        (area.y, area.height, area.x, area.width)
+    } else {
+        (area.x, area.width, area.y, area.height)
+    };
+    let probe = first_across + across / 4..first_across + across / 4 + (across / 2).max(1);
+    let hashes = |image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>| {
+        (first_line..first_line + lines)
+            .map(|line| line_hash(image, vertical, line, probe.clone()))
+            .collect::<Vec<_>>()
+    };
+    let shift = best_shift(&hashes(old), &hashes(new), scroll.max_distance)?;
+
+    let start = first_line + shift.start as u32;
+    let source = (start as i64 + shift.offset) as u32;
+    // Hashes only nominate a run; keep the prefix that really matches.
+    let len = (0..shift.len as u32)
+        .take_while(|&i| line_eq(old, new, vertical, source + i, start + i, probe.clone()))
+        .count() as u32;
+    if len < scroll.min_length.max(1) {
+        return None;
+    }
+
+    let moved = |across: u32| {
+        (0..len).all(|i| {
+            let (old_pixel, new_pixel) = if vertical {
+                (
+                    old.get_pixel(across, source + i),
+                    new.get_pixel(across, start + i),
+                )
+            } else {
+                (
+                    old.get_pixel(source + i, across),
+                    new.get_pixel(start + i, across),
+                )
+            };
+            old_pixel.channels() == new_pixel.channels()
+        })
+    };
+    let (mut low, mut high) = (probe.start, probe.end);
+    while low > first_across && moved(low - 1) {
+        low -= 1;
+    }
+    while high < first_across + across && moved(high) {
+        high += 1;
+    }
+
+    Some((
+        shift.benefit,
+        if vertical {
+            CopyRect {
+                src: Rect::new(low, source, high - low, len),
+                x: low,
+                y: start,
+            }
+        } else {
+            CopyRect {
+                src: Rect::new(source, low, len, high - low),
+                x: start,
+                y: low,
+            }
+        },
+    ))
+}
+
+/// Looks for a vertical or horizontal scroll within `area` between `old` and
+/// `new`, returning the copy that reproduces the shifted content.
+pub(crate) fn detect_scroll<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    area: Rect,
+    scroll: &Scroll,
+) -> Option<CopyRect> {
+    let area = trim(old, new, area);
+    if area.is_empty() {
+        return None;
+    }
+    let vertical = find_shift(old, new, &area, scroll, true);
+    let horizontal = find_shift(old, new, &area, scroll, false);
+    match (vertical, horizontal) {
+        (Some(v), Some(h)) if h.0 > v.0 => Some(h.1),
+        (Some(v), _) => Some(v.1),
+        (None, h) => h.map(|h| h.1),
+    }
+}
+
+const ROW_BASE: u64 = 0x9e37_79b9_7f4a_7c15;
+const COLUMN_BASE: u64 = 0xc2b2_ae3d_27d4_eb4f;
+
+#[inline(always)]
+fn pixel_hash<P: 'static + image::Pixel>(
+    image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    x: u32,
+    y: u32,
+) -> u64 {
+    hash_subpixels(hash_start(), image.get_pixel(x, y).channels())
+}
+
+/// Polynomial hash of a `width`x`height` block, matching the rolling hash of
+/// `find_blocks`.
+fn block_hash<P: 'static + image::Pixel>(
+    image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    block: &Rect,
+) -> u64 {
+    (block.y..block.bottom()).fold(0u64, |column, y| {
+        let row = (block.x..block.right()).fold(0u64, |row, x| {
+            row.wrapping_mul(ROW_BASE)
+                .wrapping_add(pixel_hash(image, x, y))
+        });
+        column.wrapping_mul(COLUMN_BASE).wrapping_add(row)
+    })
+}
+
+/// Slides a `width`x`height` window over every position inside `area` of
+/// `image`, calling `found` with the window origin whenever its hash is
+/// wanted.
+fn find_blocks<P: 'static + image::Pixel>(
+    image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    area: &Rect,
+    width: u32,
+    height: u32,
+    wanted: &HashMap<u64, Vec<usize>>,
+    mut found: impl FnMut(u32, u32, &[usize]),
+) {
+    if width > area.width || height > area.height {
+        return;
+    }
+    let positions = (area.width - width + 1) as usize;
+    let row_power = (1..width).fold(1u64, |power, _| power.wrapping_mul(ROW_BASE));
+    let column_power = (1..height).fold(1u64, |power, _| power.wrapping_mul(COLUMN_BASE));
+
+    // Row hashes of the last `height` rows, as a ring buffer.
+    let mut rows = vec![0u64; positions * height as usize];
+    let mut columns = vec![0u64; positions];
+    for y in 0..area.height {
+        let slot = (y % height) as usize * positions;
+        let mut row = 0u64;
+        for x in 0..area.width {
+            if x >= width {
+                let gone = pixel_hash(image, area.x + x - width, area.y + y);
+                row = row.wrapping_sub(gone.wrapping_mul(row_power));
+            }
+            row =
+                row.wrapping_mul(ROW_BASE)
+                    .wrapping_add(pixel_hash(image, area.x + x, area.y + y));
+            if x + 1 >= width {
+                let position = (x + 1 - width) as usize;
+                let column = &mut columns[position];
+                if y >= height {
+                    *column = column.wrapping_sub(rows[slot + position].wrapping_mul(column_power));
+                }
+                *column = column.wrapping_mul(COLUMN_BASE).wrapping_add(row);
+                rows[slot + position] = row;
+                if y + 1 >= height {
+                    if let Some(blocks) = wanted.get(column) {
+                        found(area.x + position as u32, area.y + y + 1 - height, blocks);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn block_eq<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    src: &Rect,
+    dst: &Rect,
+) -> bool {
+    (0..dst.height).all(|y| {
+        (0..dst.width).all(|x| {
+            old.get_pixel(src.x + x, src.y + y).channels()
+                == new.get_pixel(dst.x + x, dst.y + y).channels()
+        })
+    })
+}
+
+fn is_uniform<P: 'static + image::Pixel>(
+    image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    block: &Rect,
+) -> bool {
+    let first = image.get_pixel(block.x, block.y).channels();
+    (block.y..block.bottom())
+        .all(|y| (block.x..block.right()).all(|x| image.get_pixel(x, y).channels() == first))
+}
+
+/// Block hashes of the tiles of a reference frame, kept between pushes so
+/// that finding moved content only hashes around the dirty tiles of the new
+/// frame.
+///
+/// Each tile is indexed by its top-left block of the smallest tile size, the
+/// size of the search window. Clipped edge tiles and single-colour blocks are
+/// left out: the former do not fit the window, the latter match any flat
+/// background.
+pub(crate) struct MotionIndex {
+    layout: TileLayout,
+    hashes: Vec<Option<u64>>,
+    tiles: HashMap<u64, Vec<usize>>,
+}
+
+impl MotionIndex {
+    pub fn new<P: 'static + image::Pixel>(
+        image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        layout: &TileLayout,
+    ) -> Self {
+        let mut index = MotionIndex {
+            layout: *layout,
+            hashes: vec![None; (layout.columns * layout.rows) as usize],
+            tiles: HashMap::new(),
+        };
+        let mut all = DirtyMap::new(layout.columns, layout.rows);
+        for y_idx in 0..layout.rows {
+            for x_idx in 0..layout.columns {
+                all.set(x_idx, y_idx);
+            }
+        }
+        index.update(image, &all);
+        index
+    }
+
+    pub fn layout(&self) -> &TileLayout {
+        &self.layout
+    }
+
+    /// Rehashes `tiles` after they changed in `image`.
+    pub fn update<P: 'static + image::Pixel>(
+        &mut self,
+        image: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+        tiles: &DirtyMap,
+    ) {
+        let layout = self.layout;
+        for (x_idx, y_idx) in tiles.iter() {
+            let index = (y_idx * layout.columns + x_idx) as usize;
+            if let Some(hash) = self.hashes[index].take() {
+                if let Some(indices) = self.tiles.get_mut(&hash) {
+                    indices.retain(|&other| other != index);
+                    if indices.is_empty() {
+                        self.tiles.remove(&hash);
+                    }
+                }
+            }
+            let tile = layout.rect(Rect::new(x_idx, y_idx, 1, 1));
+            let block = Rect::new(tile.x, tile.y, layout.tile_width, layout.tile_height);
+            if tile.contains(&block) && !is_uniform(image, &block) {
+                let hash = block_hash(image, &block);
+                self.hashes[index] = Some(hash);
+                self.tiles.entry(hash).or_default().push(index);
+            }
+        }
+    }
+}
+
+/// Looks up the tiles of `old` in the dirty tiles of `new`, returning a copy
+/// for every tile found at a new position, grown as far as the moved content
+/// reaches within the dirty tiles.
+///
+/// Only the neighbourhood of each run of dirty tiles is hashed; `index` holds
+/// the tile hashes of `old`.
+pub(crate) fn detect_moves<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    layout: &TileLayout,
+    dirty: &DirtyMap,
+    index: &MotionIndex,
+) -> Vec<CopyRect> {
+    if index.tiles.is_empty() {
+        return Vec::new();
+    }
+    let (tile_width, tile_height) = (layout.tile_width, layout.tile_height);
+    // Whether every tile under `rect` is dirty.
+    let inside = |rect: &Rect| {
+        (layout.row(rect.y)..=layout.row(rect.bottom() - 1)).all(|y_idx| {
+            (layout.column(rect.x)..=layout.column(rect.right() - 1))
+                .all(|x_idx| dirty.get(x_idx, y_idx))
+        })
+    };
+
+    let mut copies: Vec<CopyRect> = Vec::new();
+    let mut runs: Vec<Rect> = Vec::new();
+    for (x_idx, y_idx) in dirty.iter() {
+        match runs.last_mut() {
+            Some(run) if run.y == y_idx && run.right() == x_idx => run.width += 1,
+            _ => runs.push(Rect::new(x_idx, y_idx, 1, 1)),
+        }
+    }
+    for run in runs {
+        // Every window whose origin lies in the run.
+        let origins = layout.rect(run);
+        let area = Rect::new(
+            origins.x,
+            origins.y,
+            (origins.width + tile_width - 1).min(layout.width - origins.x),
+            (origins.height + tile_height - 1).min(layout.height - origins.y),
+        );
+        find_blocks(
+            new,
+            &area,
+            tile_width,
+            tile_height,
+            &index.tiles,
+            |x, y, tiles| {
+                let dst = Rect::new(x, y, tile_width, tile_height);
+                let covered = copies.iter().any(|copy| {
+                    Rect::new(copy.x, copy.y, copy.src.width, copy.src.height).contains(&dst)
+                });
+                if covered || !inside(&dst) {
+                    return;
+                }
+                let src = tiles
+                    .iter()
+                    .map(|&tile| {
+                        let tile = index.layout.rect(Rect::new(
+                            tile as u32 % layout.columns,
+                            tile as u32 / layout.columns,
+                            1,
+                            1,
+                        ));
+                        Rect::new(tile.x, tile.y, tile_width, tile_height)
+                    })
+                    .find(|src| (src.x, src.y) != (x, y) && block_eq(old, new, src, &dst));
+                if let Some(src) = src {
+                    copies.push(grow(old, new, src, dst, inside));
+                }
+            },
+        );
+    }
+    copies
+}
+
+/// Extends a copy from `src` to `dst` one line at a time while the pixels
+/// still match and `allowed` accepts the new destination.
+fn grow<P: 'static + image::Pixel>(
+    old: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    new: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    src: Rect,
+    dst: Rect,
+    allowed: impl Fn(&Rect) -> bool,
+) -> CopyRect {
+    let (dx, dy) = (src.x as i64 - dst.x as i64, src.y as i64 - dst.y as i64);
+    let fits = |rect: &Rect| {
+        let x = rect.x as i64 + dx;
+        let y = rect.y as i64 + dy;
+        x >= 0
+            && y >= 0
+            && x + rect.width as i64 <= old.width() as i64
+            && y + rect.height as i64 <= old.height() as i64
+            && rect.right() <= new.width()
+            && rect.bottom() <= new.height()
+    };
+    let accepts = |line: &Rect| {
+        fits(line) && allowed(line) && {
+            let src = Rect::new(
+                (line.x as i64 + dx) as u32,
+                (line.y as i64 + dy) as u32,
+                line.width,
+                line.height,
+            );
+            block_eq(old, new, &src, line)
+        }
+    };
+
+    let mut dst = dst;
+    loop {
+        let mut grown = false;
+        if dst.x > 0 && accepts(&Rect::new(dst.x - 1, dst.y, 1, dst.height)) {
+            dst = Rect::new(dst.x - 1, dst.y, dst.width + 1, dst.height);
+            grown = true;
+        }
+        if accepts(&Rect::new(dst.right(), dst.y, 1, dst.height)) {
+            dst.width += 1;
+            grown = true;
+        }
+        if dst.y > 0 && accepts(&Rect::new(dst.x, dst.y - 1, dst.width, 1)) {
+            dst = Rect::new(dst.x, dst.y - 1, dst.width, dst.height + 1);
+            grown = true;
+        }
+        if accepts(&Rect::new(dst.x, dst.bottom(), dst.width, 1)) {
+            dst.height += 1;
+            grown = true;
+        }
+        if !grown {
+            break;
+        }
+    }
+    CopyRect {
+        src: Rect::new(
+            (dst.x as i64 + dx) as u32,
+            (dst.y as i64 + dy) as u32,
+            dst.width,
+            dst.height,
+        ),
+        x: dst.x,
+        y: dst.y,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::motion::{detect_moves, detect_scroll, MotionIndex, Scroll};
+    use crate::{CopyRect, DirtyMap, Grid, Rect, TileLayout};
+
+    fn stripes(offset: u32) -> image::GrayImage {
+        image::GrayImage::from_fn(32, 64, |_, y| image::Luma([((y + offset) * 7 % 251) as u8]))
+    }
+
+    #[test]
+    fn vertical_scroll() {
+        let old = stripes(0);
+        let new = stripes(5);
+        let area = Rect::new(0, 0, 32, 64);
+
+        let copy = detect_scroll(&old, &new, area, &Scroll::default());
+        assert_eq!(
+            copy,
+            Some(CopyRect {
+                src: Rect::new(0, 5, 32, 59),
+                x: 0,
+                y: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn scroll_between_borders() {
+        // A pane between static 4 px borders, with a scrollbar whose thumb
+        // moves down as the content scrolls up by 24 px.
+        let pane = |scrolled: u32, thumb: u32| {
+            image::GrayImage::from_fn(128, 128, |x, y| match x {
+                0..=3 | 124..=127 => image::Luma([200]),
+                120..=123 if (thumb..thumb + 20).contains(&y) => image::Luma([255]),
+                120..=123 => image::Luma([100]),
+                _ => image::Luma([((x * 3 + (y + scrolled) * 7) % 251) as u8]),
+            })
+        };
+        let old = pane(0, 10);
+        let new = pane(24, 30);
+        let area = Rect::new(0, 0, 128, 128);
+
+        let copy = detect_scroll(&old, &new, area, &Scroll::default());
+        assert_eq!(
+            copy,
+            Some(CopyRect {
+                src: Rect::new(4, 24, 116, 104),
+                x: 4,
+                y: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn moved_window() {
+        let mut old = image::GrayImage::new(64, 64);
+        let window = image::GrayImage::from_fn(16, 16, |x, y| image::Luma([(x * 16 + y) as u8]));
+        image::imageops::replace(&mut old, &window, 3, 5);
+        let mut new = image::GrayImage::new(64, 64);
+        image::imageops::replace(&mut new, &window, 40, 24);
+
+        let layout = TileLayout::new(
+            64,
+            64,
+            Grid::TileSize {
+                width: 8,
+                height: 8,
+            },
+        );
+        let mut dirty = DirtyMap::new(8, 8);
+        dirty.set(5, 3);
+        dirty.set(6, 3);
+        dirty.set(5, 4);
+        dirty.set(6, 4);
+
+        let index = MotionIndex::new(&old, &layout);
+        let copies = detect_moves(&old, &new, &layout, &dirty, &index);
+        assert_eq!(
+            copies,
+            vec![CopyRect {
+                src: Rect::new(3, 5, 16, 16),
+                x: 40,
+                y: 24,
+            }]
+        );
+    }
+}
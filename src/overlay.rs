@@ -0,0 +1,148 @@
+use crate::{Frame, Grid, Rect, TileLayout};
+use imageproc::drawing::{draw_hollow_rect_mut, draw_line_segment_mut};
+use num_traits::{Bounded, ToPrimitive};
+use std::path::Path;
+
+const GRID: image::Rgb<u8> = image::Rgb([64, 64, 64]);
+const PARTIAL: image::Rgb<u8> = image::Rgb([0, 255, 0]);
+const KEY: image::Rgb<u8> = image::Rgb([255, 0, 0]);
+const COPY_SOURCE: image::Rgb<u8> = image::Rgb([0, 128, 255]);
+const COPY_TARGET: image::Rgb<u8> = image::Rgb([0, 255, 255]);
+const ARROW: image::Rgb<u8> = image::Rgb([255, 255, 0]);
+
+fn to_rect(rect: &Rect) -> Option<imageproc::rect::Rect> {
+    if rect.is_empty() {
+        None
+    } else {
+        Some(
+            imageproc::rect::Rect::at(rect.x as i32, rect.y as i32)
+                .of_size(rect.width, rect.height),
+        )
+    }
+}
+
+fn center(rect: &Rect) -> (f32, f32) {
+    (
+        rect.x as f32 + rect.width as f32 / 2.0,
+        rect.y as f32 + rect.height as f32 / 2.0,
+    )
+}
+
+fn draw_arrow(image: &mut image::RgbImage, from: (f32, f32), to: (f32, f32)) {
+    draw_line_segment_mut(image, from, to, ARROW);
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length < 1.0 {
+        return;
+    }
+    let (ux, uy) = (dx / length, dy / length);
+    let head = 8.0f32.min(length / 2.0);
+    for side in [-1.0, 1.0] {
+        let tip = (
+            to.0 - head * (ux - side * uy * 0.5),
+            to.1 - head * (uy + side * ux * 0.5),
+        );
+        draw_line_segment_mut(image, to, tip, ARROW);
+    }
+}
+
+/// Draws what `push` decided over a dimmed copy of `frame`, the image that
+/// was pushed: the tiles of `grid` in gray, partial frames in green, a key
+/// frame in red, and for each copy its source in blue, its target in cyan
+/// and an arrow between them.
+///
+/// Float subpixels are taken to be in `0.0..=1.0`.
+pub fn debug_overlay<P: 'static + image::Pixel>(
+    frame: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    output: &Frame<P>,
+    grid: Grid,
+) -> image::RgbImage {
+    let max = <P as image::Pixel>::Subpixel::max_value()
+        .to_f64()
+        .filter(|max| *max <= u64::MAX as f64)
+        .unwrap_or(1.0);
+    let mut image = image::RgbImage::from_fn(frame.width(), frame.height(), |x, y| {
+        let rgb = frame.get_pixel(x, y).to_rgb();
+        image::Rgb(
+            [0, 1, 2]
+                .map(|c| (rgb[c].to_f64().unwrap_or(0.0) / max * 127.0).clamp(0.0, 127.0) as u8),
+        )
+    });
+
+    let layout = TileLayout::new(frame.width(), frame.height(), grid);
+    for y_idx in 0..layout.rows {
+        for x_idx in 0..layout.columns {
+            if let Some(tile) = to_rect(&layout.rect(Rect::new(x_idx, y_idx, 1, 1))) {
+                draw_hollow_rect_mut(&mut image, tile, GRID);
+            }
+        }
+    }
+
+    let partials = match output {
+        Frame::KeyFrame(_) => {
+            if let Some(all) = to_rect(&Rect::new(0, 0, frame.width(), frame.height())) {
+                draw_hollow_rect_mut(&mut image, all, KEY);
+            }
+            return image;
+        }
+        Frame::Unchanged => return image,
+        Frame::PartialFrame(partials) => partials,
+        Frame::CopyFrame(copies, partials) => {
+            for copy in copies {
+                let target = Rect::new(copy.x, copy.y, copy.src.width, copy.src.height);
+                if let Some(src) = to_rect(&copy.src) {
+                    draw_hollow_rect_mut(&mut image, src, COPY_SOURCE);
+                }
+                if let Some(target) = to_rect(&target) {
+                    draw_hollow_rect_mut(&mut image, target, COPY_TARGET);
+                }
+                draw_arrow(&mut image, center(&copy.src), center(&target));
+            }
+            partials
+        }
+    };
+    for partial in partials {
+        let (width, height) = partial.image.dimensions();
+        if let Some(rect) = to_rect(&Rect::new(partial.x, partial.y, width, height)) {
+            draw_hollow_rect_mut(&mut image, rect, PARTIAL);
+        }
+    }
+    image
+}
+
+/// Renders `debug_overlay` and saves it, e.g. as a PNG.
+pub fn save_debug_overlay<P: 'static + image::Pixel, Q: AsRef<Path>>(
+    frame: &image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    output: &Frame<P>,
+    grid: Grid,
+    path: Q,
+) -> image::ImageResult<()> {
+    debug_overlay(frame, output, grid).save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::overlay::{debug_overlay, GRID, PARTIAL};
+    use crate::{Frame, Grid, PartialFrame};
+
+    #[test]
+    fn draws_partial_frames() {
+        let frame = image::RgbImage::from_pixel(64, 64, image::Rgb([200, 100, 0]));
+        let output = Frame::PartialFrame(vec![PartialFrame {
+            x: 16,
+            y: 16,
+            image: image::RgbImage::new(32, 16),
+        }]);
+        let grid = Grid::TileSize {
+            width: 16,
+            height: 16,
+        };
+
+        let image = debug_overlay(&frame, &output, grid);
+        assert_eq!(image.get_pixel(5, 5), &image::Rgb([99, 49, 0]));
+        assert_eq!(image.get_pixel(0, 5), &GRID);
+        assert_eq!(image.get_pixel(16, 20), &PARTIAL);
+        assert_eq!(image.get_pixel(47, 31), &PARTIAL);
+        assert_eq!(image.get_pixel(48, 20), &GRID);
+    }
+}
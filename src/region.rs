@@ -0,0 +1,341 @@
+use crate::{DirtyMap, Rect};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Rectangle merging of dirty tiles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Merge {
+    /// Largest share of clean tiles a merged rectangle may contain, from `0.0`
+    /// (only exact unions) to `1.0` (anything goes). Accepting some clean
+    /// pixels trades bandwidth for fewer, larger crops.
+    pub max_clean_ratio: f32,
+}
+
+impl Default for Merge {
+    fn default() -> Self {
+        Merge {
+            max_clean_ratio: 0.0,
+        }
+    }
+}
+
+/// Summed-area table of a `DirtyMap` for O(1) dirty counts over a rectangle.
+struct DirtyCounts {
+    stride: usize,
+    sums: Vec<u32>,
+}
+
+impl DirtyCounts {
+    fn new(dirty: &DirtyMap) -> Self {
+        let stride = dirty.columns() as usize + 1;
+        let mut sums = vec![0; stride * (dirty.rows() as usize + 1)];
+        for y_idx in 0..dirty.rows() as usize {
+            let mut row = 0;
+            for x_idx in 0..dirty.columns() as usize {
+                row += dirty.get(x_idx as u32, y_idx as u32) as u32;
+                sums[(y_idx + 1) * stride + x_idx + 1] = sums[y_idx * stride + x_idx + 1] + row;
+            }
+        }
+        DirtyCounts { stride, sums }
+    }
+
+    fn count(&self, tiles: &Rect) -> u32 {
+        let (x0, y0) = (tiles.x as usize, tiles.y as usize);
+        let (x1, y1) = (tiles.right() as usize, tiles.bottom() as usize);
+        self.sums[y1 * self.stride + x1] + self.sums[y0 * self.stride + x0]
+            - self.sums[y0 * self.stride + x1]
+            - self.sums[y1 * self.stride + x0]
+    }
+
+    fn accepts(&self, tiles: &Rect, max_clean_ratio: f32) -> bool {
+        let area = tiles.area() as f32;
+        (area - self.count(tiles) as f32) / area <= max_clean_ratio
+    }
+}
+
+/// Joins dirty tiles into rectangles, in tile units.
+///
+/// Tiles are first joined into horizontal runs, runs with the same span are
+/// stacked vertically, and finally neighbouring rectangles are merged while
+/// the share of clean tiles stays within `merge.max_clean_ratio`.
+pub(crate) fn merge(dirty: &DirtyMap, merge: &Merge) -> Vec<Rect> {
+    let counts = DirtyCounts::new(dirty);
+    let max_clean_ratio = merge.max_clean_ratio;
+
+    let mut rects: Vec<Rect> = Vec::new();
+    // Rectangles that ended on the previous row and may still grow downwards.
+    let mut open: Vec<usize> = Vec::new();
+    for y_idx in 0..dirty.rows() {
+        let mut runs: Vec<Rect> = Vec::new();
+        for x_idx in 0..dirty.columns() {
+            if !dirty.get(x_idx, y_idx) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run)
+                    if counts.accepts(
+                        &Rect::new(run.x, y_idx, x_idx + 1 - run.x, 1),
+                        max_clean_ratio,
+                    ) =>
+                {
+                    run.width = x_idx + 1 - run.x;
+                }
+                _ => runs.push(Rect::new(x_idx, y_idx, 1, 1)),
+            }
+        }
+
+        let mut next_open = Vec::with_capacity(runs.len());
+        for run in runs {
+            match open
+                .iter()
+                .find(|&&index| rects[index].x == run.x && rects[index].width == run.width)
+            {
+                Some(&index) => {
+                    rects[index].height += 1;
+                    next_open.push(index);
+                }
+                None => {
+                    next_open.push(rects.len());
+                    rects.push(run);
+                }
+            }
+        }
+        open = next_open;
+    }
+
+    if max_clean_ratio > 0.0 {
+        rects = merge_neighbours(rects, dirty, &counts, max_clean_ratio);
+        rects.sort_by_key(|rect| (rect.y, rect.x));
+    }
+    rects
+}
+
+/// Disjoint rectangles in tile units, with the owner of every tile.
+struct Owners {
+    columns: u32,
+    rows: u32,
+    rects: Vec<Option<Rect>>,
+    tiles: Vec<Option<usize>>,
+}
+
+impl Owners {
+    fn owner(&self, x_idx: i64, y_idx: i64) -> Option<usize> {
+        if x_idx < 0 || y_idx < 0 || x_idx >= self.columns as i64 || y_idx >= self.rows as i64 {
+            return None;
+        }
+        self.tiles[(y_idx as u32 * self.columns + x_idx as u32) as usize]
+    }
+
+    /// Owners of the tiles on the outline of `rect`, grown by `grow` tiles.
+    fn outline(&self, rect: &Rect, grow: i64) -> Vec<usize> {
+        let (left, top) = (rect.x as i64 - grow, rect.y as i64 - grow);
+        let (right, bottom) = (
+            rect.right() as i64 - 1 + grow,
+            rect.bottom() as i64 - 1 + grow,
+        );
+        let horizontal = (left..=right).flat_map(|x_idx| [(x_idx, top), (x_idx, bottom)]);
+        let vertical = (top..=bottom).flat_map(|y_idx| [(left, y_idx), (right, y_idx)]);
+        let mut owners: Vec<usize> = horizontal
+            .chain(vertical)
+            .filter_map(|(x_idx, y_idx)| self.owner(x_idx, y_idx))
+            .collect();
+        owners.sort_unstable();
+        owners.dedup();
+        owners
+    }
+
+    /// Whether some rectangle crosses the edge of `union`. Such a rectangle
+    /// must own a tile just inside it, so only the outline is checked.
+    fn overlaps(&self, union: &Rect) -> bool {
+        self.outline(union, 0)
+            .into_iter()
+            .any(|index| self.rects[index].is_some_and(|rect| !union.contains(&rect)))
+    }
+}
+
+/// Greedily merges touching rectangles, cheapest union first, while the
+/// share of clean tiles stays within `max_clean_ratio` and no other rectangle
+/// would be cut.
+///
+/// Candidate pairs are kept in a heap and only formed between neighbours
+/// found through the tile owners, so every merge costs the outline and area
+/// of the union rather than a scan over all pairs.
+fn merge_neighbours(
+    rects: Vec<Rect>,
+    dirty: &DirtyMap,
+    counts: &DirtyCounts,
+    max_clean_ratio: f32,
+) -> Vec<Rect> {
+    let mut owners = Owners {
+        columns: dirty.columns(),
+        rows: dirty.rows(),
+        rects: Vec::with_capacity(rects.len() * 2),
+        tiles: vec![None; dirty.columns() as usize * dirty.rows() as usize],
+    };
+    // Pairs ordered by the clean tiles their union adds.
+    let mut candidates: BinaryHeap<Reverse<(u64, usize, usize)>> = BinaryHeap::new();
+    let candidates_of = |owners: &Owners, index: usize| {
+        let rect = owners.rects[index].expect("live rectangle");
+        owners
+            .outline(&rect, 1)
+            .into_iter()
+            .filter(|&other| other != index)
+            .filter_map(|other| {
+                let union = rect.union(&owners.rects[other]?);
+                counts
+                    .accepts(&union, max_clean_ratio)
+                    .then(|| Reverse((union.area() - counts.count(&union) as u64, other, index)))
+            })
+            .collect::<Vec<_>>()
+    };
+    let place = |owners: &mut Owners, rect: Rect| {
+        let index = owners.rects.len();
+        for y_idx in rect.y..rect.bottom() {
+            for x_idx in rect.x..rect.right() {
+                let tile = (y_idx * owners.columns + x_idx) as usize;
+                if let Some(inner) = owners.tiles[tile].replace(index) {
+                    owners.rects[inner] = None;
+                }
+            }
+        }
+        owners.rects.push(Some(rect));
+        index
+    };
+
+    for rect in rects {
+        place(&mut owners, rect);
+    }
+    for index in 0..owners.rects.len() {
+        candidates.extend(candidates_of(&owners, index));
+    }
+    while let Some(Reverse((_, i, j))) = candidates.pop() {
+        let (a, b) = match (owners.rects[i], owners.rects[j]) {
+            (Some(a), Some(b)) => (a, b),
+            _ => continue,
+        };
+        let union = a.union(&b);
+        if owners.overlaps(&union) {
+            continue;
+        }
+        let index = place(&mut owners, union);
+        candidates.extend(candidates_of(&owners, index));
+    }
+    owners.rects.into_iter().flatten().collect()
+}
+
+/// Collects dirty regions top-down over a quadtree of tiles, in tile units.
+///
+/// A node is returned whole once at least `min_dirty_ratio` of its tiles are
+/// dirty; otherwise only its dirty quadrants are visited, down to single tiles.
+pub(crate) fn quadtree(dirty: &DirtyMap, min_dirty_ratio: f32) -> Vec<Rect> {
+    fn visit(
+        counts: &DirtyCounts,
+        bounds: &Rect,
+        node: Rect,
+        min_dirty_ratio: f32,
+        rects: &mut Vec<Rect>,
+    ) {
+        let clipped = Rect::new(
+            node.x,
+            node.y,
+            node.width.min(bounds.right().saturating_sub(node.x)),
+            node.height.min(bounds.bottom().saturating_sub(node.y)),
+        );
+        if clipped.is_empty() {
+            return;
+        }
+        let count = counts.count(&clipped);
+        if count == 0 {
+            return;
+        }
+        if node.width == 1 || count as f32 >= min_dirty_ratio * clipped.area() as f32 {
+            rects.push(clipped);
+            return;
+        }
+        let half = node.width / 2;
+        for (x, y) in [(0, 0), (half, 0), (0, half), (half, half)] {
+            let child = Rect::new(node.x + x, node.y + y, half, half);
+            visit(counts, bounds, child, min_dirty_ratio, rects);
+        }
+    }
+
+    let counts = DirtyCounts::new(dirty);
+    let bounds = Rect::new(0, 0, dirty.columns(), dirty.rows());
+    let size = dirty.columns().max(dirty.rows()).max(1).next_power_of_two();
+    let mut rects = Vec::new();
+    visit(
+        &counts,
+        &bounds,
+        Rect::new(0, 0, size, size),
+        min_dirty_ratio,
+        &mut rects,
+    );
+    rects
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::region::{merge, Merge};
+    use crate::{DirtyMap, Rect};
+
+    fn map(rows: &[&str]) -> DirtyMap {
+        let mut dirty = DirtyMap::new(rows[0].len() as u32, rows.len() as u32);
+        for (y_idx, row) in rows.iter().enumerate() {
+            for (x_idx, c) in row.chars().enumerate() {
+                if c == '1' {
+                    dirty.set(x_idx as u32, y_idx as u32);
+                }
+            }
+        }
+        dirty
+    }
+
+    #[test]
+    fn exact_and_lossy() {
+        let dirty = map(&["1100", "1101", "0001"]);
+
+        let rects = merge(&dirty, &Merge::default());
+        assert_eq!(rects, vec![Rect::new(0, 0, 2, 2), Rect::new(3, 1, 1, 2)]);
+
+        let rects = merge(
+            &dirty,
+            &Merge {
+                max_clean_ratio: 0.5,
+            },
+        );
+        assert_eq!(rects, vec![Rect::new(0, 0, 4, 3)]);
+    }
+
+    #[test]
+    fn merge_large_grid() {
+        // 1080p with 16 px tiles and a pseudo-random fifth of them dirty.
+        let mut dirty = DirtyMap::new(120, 68);
+        let mut state = 12345u32;
+        for y_idx in 0..68 {
+            for x_idx in 0..120 {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                if (state >> 16).is_multiple_of(5) {
+                    dirty.set(x_idx, y_idx);
+                }
+            }
+        }
+
+        for max_clean_ratio in [0.2, 0.6] {
+            let rects = merge(&dirty, &Merge { max_clean_ratio });
+
+            let mut covered = DirtyMap::new(120, 68);
+            for (i, rect) in rects.iter().enumerate() {
+                assert!(rects[i + 1..].iter().all(|other| !rect.intersects(other)));
+                let clean = (rect.y..rect.bottom())
+                    .flat_map(|y_idx| (rect.x..rect.right()).map(move |x_idx| (x_idx, y_idx)))
+                    .filter(|&(x_idx, y_idx)| {
+                        covered.set(x_idx, y_idx);
+                        !dirty.get(x_idx, y_idx)
+                    })
+                    .count();
+                assert!(clean as f32 <= max_clean_ratio * rect.area() as f32);
+            }
+            assert!(dirty.iter().all(|(x_idx, y_idx)| covered.get(x_idx, y_idx)));
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use crate::KeyframeReason;
+use std::time::Duration;
+
+/// What a single push found and sent.
+///
+/// Key frames sent without diffing report every tile as dirty.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FrameStats {
+    pub dirty_tiles: usize,
+    pub total_tiles: usize,
+    /// Area of the dirty tiles in pixels, or of the bounds of their changed
+    /// pixels with `tight`. Without `tight` this follows the grid, not the
+    /// number of pixels that changed.
+    pub dirty_area: u64,
+    /// Pixels that differ from the reference. Only counted with `tight`, which
+    /// scans the changed rows anyway; `None` otherwise, in `hash_tiles` mode
+    /// and for key frames.
+    pub changed_pixels: Option<u64>,
+    /// `dirty_area` over the area of the frame.
+    pub dirty_ratio: f32,
+    /// Pixel data in the emitted key frame or partial frames.
+    pub bytes: usize,
+    /// Time spent finding changes, copies and rectangles.
+    pub diff_time: Duration,
+    /// Time spent cropping partial frames and updating the reference.
+    pub crop_time: Duration,
+    /// Why a key frame was sent, if one was.
+    pub keyframe: Option<KeyframeReason>,
+}
@@ -0,0 +1,169 @@
+use crate::mask::Masks;
+use crate::{DecodeError, Frame, FrameContext, FrameDecoder};
+use std::time::Duration;
+
+/// Why a `Verifier` push failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    Decode(DecodeError),
+    /// The reconstruction has a different size than the pushed frame.
+    Size {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// The first pixel, in row-major order, where the reconstruction differs
+    /// from the pushed frame.
+    Mismatch {
+        x: u32,
+        y: u32,
+    },
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Decode(err) => write!(f, "decoding failed: {}", err),
+            VerifyError::Size { expected, actual } => write!(
+                f,
+                "decoded {}x{} instead of {}x{}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+            VerifyError::Mismatch { x, y } => write!(f, "decoded frame differs at {},{}", x, y),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<DecodeError> for VerifyError {
+    fn from(err: DecodeError) -> Self {
+        VerifyError::Decode(err)
+    }
+}
+
+/// Runs a `FrameDecoder` next to a `FrameContext` and checks after every
+/// push that the decoded frame matches the pushed one.
+///
+/// Pixels are compared with `context.compare`, so lossy comparators pass as
+/// long as the reconstruction is within their tolerance, and ignore regions
+/// not reported on a push are skipped. Pushes with damage hints are not
+/// supported, as changes outside the damage are left out on purpose.
+pub struct Verifier<P: image::Pixel> {
+    pub context: FrameContext<P>,
+    decoder: FrameDecoder<P>,
+}
+
+impl<P: 'static + image::Pixel> Verifier<P>
+where
+    P: image::Pixel + std::cmp::PartialEq + Send + Sync,
+    <P as image::Pixel>::Subpixel: Send + Sync,
+{
+    pub fn new(
+        timestamp: Duration,
+        limits: usize,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    ) -> Self {
+        let mut decoder = FrameDecoder::new();
+        // The receiver starts from the same frame as the context.
+        decoder
+            .apply(&Frame::KeyFrame(frame.clone()))
+            .expect("key frames always decode");
+        Verifier {
+            context: FrameContext::new(timestamp, limits, frame),
+            decoder,
+        }
+    }
+
+    pub fn decoder(&self) -> &FrameDecoder<P> {
+        &self.decoder
+    }
+
+    /// Pushes `frame` and verifies the result. On error the output is lost
+    /// and the decoder may be out of sync; a later key frame resyncs it.
+    pub fn push(
+        &mut self,
+        timestamp: &Duration,
+        frame: image::ImageBuffer<P, Vec<<P as image::Pixel>::Subpixel>>,
+    ) -> Result<Frame<P>, VerifyError> {
+        let expected = frame.clone();
+        let output = self.context.push(timestamp, frame);
+        self.decoder.apply(&output)?;
+
+        let decoded = self.decoder.frame().ok_or(DecodeError::NoKeyFrame)?;
+        if decoded.dimensions() != expected.dimensions() {
+            return Err(VerifyError::Size {
+                expected: expected.dimensions(),
+                actual: decoded.dimensions(),
+            });
+        }
+        let masks = match output {
+            // Key frames send everything, masked or not.
+            Frame::KeyFrame(_) => Masks::active(&[], 0),
+            _ => Masks::active(&self.context.ignore, self.context.pushes),
+        };
+        let mismatch = expected.enumerate_pixels().zip(decoded.pixels()).find(
+            |((x, y, expected), decoded)| {
+                !masks.contains(*x, *y) && self.context.compare.differs(expected, decoded)
+            },
+        );
+        match mismatch {
+            Some(((x, y, _), _)) => Err(VerifyError::Mismatch { x, y }),
+            None => Ok(output),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Comparator, Ignore, Mask, Merge, Rect, Scroll, Verifier, VerifyError};
+    use std::time::Duration;
+
+    fn scene(n: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(100, 70, |x, y| {
+            let moving = (10 + n * 7..30 + n * 7).contains(&x) && (20..35).contains(&y);
+            let noise = ((x * 31 + y * 17 + n) % 3) as u8;
+            if moving {
+                image::Rgb([255, 0, 0])
+            } else if y > 60 {
+                image::Rgb([n as u8 * 20, 0, 0])
+            } else {
+                image::Rgb([x as u8 + noise, (y + n * 3) as u8, 0])
+            }
+        })
+    }
+
+    #[test]
+    fn lossy_modes_round_trip() {
+        let mut verifier = Verifier::new(Duration::from_secs(0), 4, scene(0));
+        verifier.context.compare = Comparator::Channel(2.0);
+        verifier.context.merge = Some(Merge {
+            max_clean_ratio: 0.3,
+        });
+        verifier.context.tight = true;
+        verifier.context.scroll = Some(Scroll::default());
+        verifier.context.motion = true;
+        verifier.context.ignore.push(Ignore {
+            mask: Mask::Rect(Rect::new(0, 61, 100, 9)),
+            interval: 3,
+        });
+        for n in 1..10 {
+            if let Err(err) = verifier.push(&Duration::from_secs(n as u64), scene(n)) {
+                panic!("push {}: {}", n, err);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_first_mismatch() {
+        let mut verifier = Verifier::new(Duration::from_secs(0), 10, image::RgbImage::new(32, 32));
+        let mut img = image::RgbImage::new(32, 32);
+        img.put_pixel(3, 7, image::Rgb([9, 9, 9]));
+        img.put_pixel(20, 20, image::Rgb([9, 9, 9]));
+        // A reference that already has the change hides it from the diff.
+        verifier.context.frame = Some(img.clone());
+        assert_eq!(
+            verifier.push(&Duration::from_secs(1), img).err(),
+            Some(VerifyError::Mismatch { x: 3, y: 7 })
+        );
+    }
+}